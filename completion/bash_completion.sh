@@ -1,6 +1,6 @@
 _feed_list()
 {
-    [ -z "${FEED_DIR}" ] && FEED_DIR="${XDG_CONFIG_DIR:-${HOME}/.config}/feeds/"
+    local FEED_DIR="$(feed-config feed_dir 2>/dev/null)"
     [ ! -d "${FEED_DIR}" ] && return 1
 
     local cur prev OPTS
@@ -16,7 +16,7 @@ _feed_list()
 
 _feed_list_unread()
 {
-    [ -z "${FEED_DIR}" ] && FEED_DIR="${XDG_CONFIG_DIR:-${HOME}/.config}/feeds/"
+    local FEED_DIR="$(feed-config feed_dir 2>/dev/null)"
     [ ! -d "${FEED_DIR}" ] && return 1
 
     local cur prev OPTS
@@ -31,6 +31,18 @@ _feed_list_unread()
 }
 
 complete -F _feed_list feed-delete
+complete -F _feed_list feed-rename
+complete -F _feed_list feed-merge
 complete -F _feed_list_unread feed-read
-complete -F _feed_list feed-update
+complete -F _feed_list -W "-j" feed-update
 complete -F _feed_list feed-markasread
+complete -F _feed_list feed-star
+complete -F _feed_list feed-unstar
+complete -F _feed_list feed-saved
+complete -F _feed_list feed-export
+complete -F _feed_list feed-health
+complete -W "export import" feed-opml
+complete -W "profile feed_dir database backend backups seen_days open fetch lock.wait lock.method update.skip update.jobs update.per_host update.timeout update.max_size" feed-config
+complete -W "tsv sqlite sharded" feed-migrate
+complete -W "-f" feed-unlock
+complete -W "-n" feed-undo
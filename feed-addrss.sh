@@ -7,8 +7,13 @@
 
 set -e
 
-[ -z "${FEED_DIR}" ] && FEED_DIR="${XDG_CONFIG_DIR:-${HOME}/.config}/feeds/"
 export PATH="${PATH}:$(dirname "$0")"
+if [ "$1" = "--profile" ] && [ "$#" -gt 1 ]; then
+    # Passed on to the feed-* programs
+    export FEEDUTILS_PROFILE="$2"
+    shift 2
+fi
+FEED_DIR="$(feed-config feed_dir)" || exit 1
 
 if [ ! -d "${FEED_DIR}" ]; then
     printf "%s: feed dir '%s' does not exist\n" "$0" "${FEED_DIR}" 1>&2
@@ -16,7 +21,7 @@ if [ ! -d "${FEED_DIR}" ]; then
 fi
 
 if [ "$#" -ne 2 ]; then
-    printf 'usage: %s <name> <rss>\n' "$0" 1>&2
+    printf 'usage: %s [--profile <name>] <name> <rss>\n' "$0" 1>&2
     exit 1
 fi
 name="$1"
@@ -26,9 +31,7 @@ cd "${FEED_DIR}"
 mkdir "${name}"
 cd "${name}"
 ln -s ../browser-open.sh ./open
-printf '#!/usr/bin/env sh\n' >> fetch
-printf "exec curl -L -o - '%s'\n" "${rss}" >> fetch
-chmod +x fetch
+printf '%s\n' "${rss}" > url
 feed-update "${name}"
 feed-markasread "${name}"
 printf 'Added feed %s\n' "${name}"
@@ -5,8 +5,13 @@
 # Author:   Alastair Hughes
 # Contact:  hobbitalastair at yandex dot com
 
-[ -z "${FEED_DIR}" ] && FEED_DIR="${XDG_CONFIG_DIR:-${HOME}/.config}/feeds/"
 export PATH="${PATH}:$(dirname "$0")"
+if [ "$1" = "--profile" ] && [ "$#" -gt 1 ]; then
+    # Passed on to the feed-* programs
+    export FEEDUTILS_PROFILE="$2"
+    shift 2
+fi
+FEED_DIR="$(feed-config feed_dir)" || exit 1
 
 if [ ! -d "${FEED_DIR}" ]; then
     printf "%s: feed dir '%s' does not exist\n" "$0" "${FEED_DIR}" 1>&2
@@ -14,7 +19,7 @@ if [ ! -d "${FEED_DIR}" ]; then
 fi
 
 if [ "$#" -ne 0 ]; then
-    printf 'usage: %s\n' "$0" 1>&2
+    printf 'usage: %s [--profile <name>]\n' "$0" 1>&2
     exit 1
 fi
 
@@ -0,0 +1,569 @@
+// Configuration.
+// Settings are read from feedutils.toml in the XDG config directory (or the
+// file named by FEEDUTILS_CONFIG), and can be overridden by the older
+// FEEDUTILS_* environment variables. Everything has a default, so the file
+// is optional.
+// Profiles are named sets of feeds, each with their own feed directory and
+// database, sharing the other settings. The profile is chosen by the
+// --profile option, which is passed on to other programs (eg open scripts)
+// in FEEDUTILS_PROFILE, or else by the profile setting.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::time;
+
+use thiserror::Error;
+
+use crate::{Backend, LockMethod, LockWait, StoreOptions};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UpdateOptions {
+    // Feeds left out when updating every feed
+    pub skip: Vec<String>,
+    // Number of feeds to fetch at once
+    pub jobs: usize,
+    // Number of feeds to fetch at once from any one host, if limited
+    pub per_host: Option<usize>,
+    // Time a fetch may take before it is terminated, if limited; feeds can
+    // override this with a timeout file
+    pub timeout: Option<time::Duration>,
+    // Most output kept from a fetch, in bytes
+    pub max_size: u64,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> UpdateOptions {
+        return UpdateOptions {
+            skip: Vec::new(),
+            jobs: 4,
+            per_host: None,
+            timeout: Some(time::Duration::from_secs(300)),
+            max_size: 16 * 1024 * 1024,
+        };
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Profile {
+    // Defaults to a feeds-<profile> directory beside the usual one
+    pub feed_dir: Option<PathBuf>,
+    // Defaults to a feedutils-<profile> database beside the usual one
+    pub database: Option<PathBuf>,
+    pub backend: Option<Backend>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Config {
+    // Profile in use, if any
+    pub profile: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+    // Directory containing a directory per feed
+    pub feed_dir: Option<PathBuf>,
+    // Database path for the configured backend, if not the default
+    pub database: Option<PathBuf>,
+    pub backend: Backend,
+    pub store: StoreOptions,
+    // Shell commands run for feeds without their own open or fetch
+    // executable
+    pub open: Option<String>,
+    pub fetch: Option<String>,
+    pub update: UpdateOptions,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config: {source}: {path}")]
+    ReadError {
+        source: io::Error,
+        path: PathBuf,
+    },
+    #[error("Invalid config: {path}: {source}")]
+    ParseError {
+        source: Box<toml::de::Error>,
+        path: PathBuf,
+    },
+    #[error("Unknown setting {name}: {path}")]
+    UnknownSetting {
+        name: String,
+        path: PathBuf,
+    },
+    #[error("Invalid value for {name}: {value}")]
+    InvalidSetting {
+        name: String,
+        value: String,
+    },
+    #[error("Missing profile name for --profile")]
+    MissingProfileName,
+}
+
+pub fn get_args() -> Result<Vec<String>, ConfigError> {
+    // The program's arguments, less a leading --profile option. The profile
+    // is put in FEEDUTILS_PROFILE, so that programs run from this one use
+    // the same profile.
+
+    let mut args: Vec<String> = env::args().collect();
+    let profile = match args.get(1).map(|arg| arg.as_str()) {
+        Some("--profile") if args.len() > 2 => {
+            let profile = args.remove(2);
+            args.remove(1);
+            profile
+        },
+        Some("--profile") => return Err(ConfigError::MissingProfileName),
+        Some(arg) if arg.starts_with("--profile=") => args.remove(1)["--profile=".len()..].to_string(),
+        _ => return Ok(args),
+    };
+    if profile.is_empty() {
+        return Err(ConfigError::MissingProfileName);
+    }
+    env::set_var("FEEDUTILS_PROFILE", profile);
+    return Ok(args);
+}
+
+pub fn load_or_exit() -> (Vec<String>, Config) {
+    // The arguments and config every program starts with, reporting any
+    // error and exiting.
+    let result = get_args().and_then(|args| Ok((args, Config::load()?)));
+    match result {
+        Ok((args, config)) => return (args, config),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    }
+}
+
+pub fn get_config_path() -> Option<PathBuf> {
+    // Config file path; check possible settings env vars in sequence.
+    // Does not check if the file actually exists.
+
+    if let Some(path) = env::var_os("FEEDUTILS_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    if let Some(path) = env::var_os("XDG_CONFIG_HOME") {
+        return Some(PathBuf::from(path).join("feedutils.toml"));
+    }
+    if let Some(path) = env::var_os("HOME") {
+        return Some(PathBuf::from(path).join(".config/feedutils.toml"));
+    }
+
+    return None;
+}
+
+fn default_feed_dir(profile: Option<&str>) -> Option<PathBuf> {
+    let name = match profile {
+        Some(profile) => format!("feeds-{}", profile),
+        None => "feeds".to_string(),
+    };
+    if let Some(path) = env::var_os("XDG_CONFIG_HOME") {
+        return Some(PathBuf::from(path).join(name));
+    }
+    if let Some(path) = env::var_os("HOME") {
+        return Some(PathBuf::from(path).join(".config").join(name));
+    }
+    return None;
+}
+
+fn expand_path(path: &str) -> PathBuf {
+    // Paths in the config file may start with ~ for the home directory.
+    if let (Some(rest), Some(home)) = (path.strip_prefix("~/"), env::var_os("HOME")) {
+        return PathBuf::from(home).join(rest);
+    }
+    return PathBuf::from(path);
+}
+
+fn parse_lock_wait(value: &str) -> Option<LockWait> {
+    // Either "fail", "block" or a timeout in seconds.
+    return match value {
+        "fail" => Some(LockWait::FailFast),
+        "block" => Some(LockWait::Block),
+        _ => value.parse().ok()
+            .and_then(|seconds| time::Duration::try_from_secs_f64(seconds).ok())
+            .map(LockWait::Timeout),
+    };
+}
+
+pub fn parse_timeout(value: &str) -> Option<Option<time::Duration>> {
+    // A timeout in seconds, where 0 means no timeout.
+    let seconds: f64 = value.trim().parse().ok()?;
+    if seconds == 0.0 {
+        return Some(None);
+    }
+    return time::Duration::try_from_secs_f64(seconds).ok().map(Some);
+}
+
+fn parse_lock_method(value: &str) -> Option<LockMethod> {
+    return match value {
+        "lockfile" => Some(LockMethod::LockFile),
+        "flock" => Some(LockMethod::Flock),
+        _ => None,
+    };
+}
+
+fn invalid(name: &str, value: &dyn ToString) -> ConfigError {
+    return ConfigError::InvalidSetting{ name: name.to_string(), value: value.to_string() };
+}
+
+fn string_value<'a>(name: &str, value: &'a toml::Value) -> Result<&'a str, ConfigError> {
+    return value.as_str().ok_or_else(|| invalid(name, value));
+}
+
+fn integer_value<T: TryFrom<i64>>(name: &str, value: &toml::Value) -> Result<T, ConfigError> {
+    return value.as_integer().and_then(|number| T::try_from(number).ok()).ok_or_else(|| invalid(name, value));
+}
+
+fn table_value<'a>(name: &str, value: &'a toml::Value) -> Result<&'a toml::Table, ConfigError> {
+    return value.as_table().ok_or_else(|| invalid(name, value));
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        return Config {
+            profile: None,
+            profiles: BTreeMap::new(),
+            feed_dir: default_feed_dir(None),
+            database: None,
+            backend: Backend::Tsv,
+            store: StoreOptions::default(),
+            open: None,
+            fetch: None,
+            update: UpdateOptions::default(),
+        };
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        // The defaults, overridden by the config file if there is one, then
+        // by environment variables, and then by the profile in use. A profile
+        // names its own feeds and database, so FEEDUTILS_CONFIGDIR and
+        // FEEDUTILS_DB (which set those for no profile) mustn't point it
+        // elsewhere.
+
+        let mut file = None;
+        if let Some(path) = get_config_path() {
+            match fs::read_to_string(&path) {
+                Ok(contents) => file = Some((contents, path)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(ConfigError::ReadError{ source: e, path }),
+            }
+        }
+        return Config::from_sources(file, |name| env::var_os(name));
+    }
+
+    fn from_sources<V>(file: Option<(String, PathBuf)>, var: V) -> Result<Config, ConfigError>
+        where V: Fn(&str) -> Option<OsString>
+    {
+        // Build the config from the file's contents and path, if there is a
+        // file, and environment variables looked up with var.
+        let mut config = Config::default();
+        if let Some((contents, path)) = file {
+            config.read_file(&contents, path)?;
+        }
+        if let Some(profile) = var("FEEDUTILS_PROFILE").and_then(|profile| profile.into_string().ok()) {
+            config.profile = Some(profile);
+        }
+        config.read_env(&var)?;
+        config.use_profile();
+        return Ok(config);
+    }
+
+    fn use_profile(&mut self) {
+        // A profile has its own feeds and database, rather than those
+        // configured for no profile. Profiles which aren't in the config file
+        // use the defaults for their name.
+        let name = match &self.profile {
+            Some(name) => name.clone(),
+            None => return,
+        };
+        let profile = self.profiles.get(&name).cloned().unwrap_or_default();
+        self.feed_dir = profile.feed_dir.or(default_feed_dir(Some(&name)));
+        self.database = profile.database;
+        if let Some(backend) = profile.backend {
+            self.backend = backend;
+        }
+    }
+
+    fn read_file(&mut self, contents: &str, path: PathBuf) -> Result<(), ConfigError> {
+        let table: toml::Table = contents.parse()
+            .map_err(|e| ConfigError::ParseError{ source: Box::new(e), path: path.clone() })?;
+        let unknown = |name: &str| ConfigError::UnknownSetting{ name: name.to_string(), path: path.clone() };
+
+        for (name, value) in table.iter() {
+            match name.as_str() {
+                "feed_dir" => self.feed_dir = Some(expand_path(string_value(name, value)?)),
+                "database" => self.database = Some(expand_path(string_value(name, value)?)),
+                "backend" => {
+                    let backend = string_value(name, value)?;
+                    self.backend = Backend::from_name(backend).map_err(|_| invalid(name, value))?;
+                },
+                "backups" => self.store.backups = integer_value(name, value)?,
+                "seen_days" => self.store.seen_days = integer_value(name, value)?,
+                "open" => self.open = Some(string_value(name, value)?.to_string()),
+                "fetch" => self.fetch = Some(string_value(name, value)?.to_string()),
+                "lock" => {
+                    for (name, value) in table_value(name, value)? {
+                        let name = format!("lock.{}", name);
+                        match name.as_str() {
+                            "lock.wait" => {
+                                // A number of seconds, or a string
+                                let wait = value.as_str().map(|wait| wait.to_string()).unwrap_or(value.to_string());
+                                self.store.lock.wait = parse_lock_wait(&wait).ok_or_else(|| invalid(&name, value))?;
+                            },
+                            "lock.method" => {
+                                let method = string_value(&name, value)?;
+                                self.store.lock.method = parse_lock_method(method).ok_or_else(|| invalid(&name, value))?;
+                            },
+                            _ => return Err(unknown(&name)),
+                        }
+                    }
+                },
+                "profile" => self.profile = Some(string_value(name, value)?.to_string()),
+                "profiles" => {
+                    for (profile_name, value) in table_value(name, value)? {
+                        let mut profile = Profile::default();
+                        for (name, value) in table_value(&format!("profiles.{}", profile_name), value)? {
+                            let name = format!("profiles.{}.{}", profile_name, name);
+                            match name.rsplit('.').next().unwrap_or_default() {
+                                "feed_dir" => profile.feed_dir = Some(expand_path(string_value(&name, value)?)),
+                                "database" => profile.database = Some(expand_path(string_value(&name, value)?)),
+                                "backend" => {
+                                    let backend = string_value(&name, value)?;
+                                    profile.backend = Some(Backend::from_name(backend).map_err(|_| invalid(&name, value))?);
+                                },
+                                _ => return Err(unknown(&name)),
+                            }
+                        }
+                        self.profiles.insert(profile_name.clone(), profile);
+                    }
+                },
+                "update" => {
+                    for (name, value) in table_value(name, value)? {
+                        let name = format!("update.{}", name);
+                        match name.as_str() {
+                            "update.skip" => {
+                                let feeds = value.as_array().ok_or_else(|| invalid(&name, value))?;
+                                self.update.skip = feeds.iter()
+                                    .map(|feed| string_value(&name, feed).map(|feed| feed.to_string()))
+                                    .collect::<Result<Vec<String>, ConfigError>>()?;
+                            },
+                            "update.jobs" => {
+                                self.update.jobs = integer_value(&name, value)?;
+                                if self.update.jobs == 0 {
+                                    return Err(invalid(&name, value));
+                                }
+                            },
+                            "update.per_host" => {
+                                let per_host: usize = integer_value(&name, value)?;
+                                if per_host == 0 {
+                                    return Err(invalid(&name, value));
+                                }
+                                self.update.per_host = Some(per_host);
+                            },
+                            "update.timeout" => {
+                                // A number of seconds, integer or not
+                                self.update.timeout = parse_timeout(&value.to_string()).ok_or_else(|| invalid(&name, value))?;
+                            },
+                            "update.max_size" => {
+                                self.update.max_size = integer_value(&name, value)?;
+                                if self.update.max_size == 0 {
+                                    return Err(invalid(&name, value));
+                                }
+                            },
+                            _ => return Err(unknown(&name)),
+                        }
+                    }
+                },
+                _ => return Err(unknown(name)),
+            }
+        }
+        return Ok(());
+    }
+
+    fn read_env(&mut self, var: &dyn Fn(&str) -> Option<OsString>) -> Result<(), ConfigError> {
+        // The environment variables predate the config file, so still take
+        // precedence over it.
+        let string_var = |name: &str| var(name).and_then(|value| value.into_string().ok());
+
+        if let Some(path) = var("FEEDUTILS_CONFIGDIR") {
+            self.feed_dir = Some(PathBuf::from(path));
+        }
+        if let Some(path) = var("FEEDUTILS_DB") {
+            self.database = Some(PathBuf::from(path));
+        }
+        if let Some(value) = string_var("FEEDUTILS_BACKEND") {
+            self.backend = Backend::from_name(&value).map_err(|_| invalid("FEEDUTILS_BACKEND", &value))?;
+        }
+        if let Some(value) = string_var("FEEDUTILS_LOCK_WAIT") {
+            self.store.lock.wait = parse_lock_wait(&value).ok_or_else(|| invalid("FEEDUTILS_LOCK_WAIT", &value))?;
+        }
+        if let Some(value) = string_var("FEEDUTILS_LOCK_METHOD") {
+            self.store.lock.method = parse_lock_method(&value).ok_or_else(|| invalid("FEEDUTILS_LOCK_METHOD", &value))?;
+        }
+        if let Some(value) = string_var("FEEDUTILS_BACKUPS") {
+            self.store.backups = value.parse().map_err(|_| invalid("FEEDUTILS_BACKUPS", &value))?;
+        }
+        if let Some(value) = string_var("FEEDUTILS_SEEN_DAYS") {
+            self.store.seen_days = value.parse().map_err(|_| invalid("FEEDUTILS_SEEN_DAYS", &value))?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(contents: Option<&str>, vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let file = contents.map(|contents| (contents.to_string(), PathBuf::from("feedutils.toml")));
+        let vars: BTreeMap<String, OsString> = vars.iter().map(|(name, value)| (name.to_string(), OsString::from(value))).collect();
+        return Config::from_sources(file, |name| vars.get(name).cloned());
+    }
+
+    const PROFILES: &str = r#"
+        feed_dir = "/feeds"
+        backend = "sharded"
+        backups = 2
+        profile = "personal"
+
+        [profiles.personal]
+        [profiles.work]
+        feed_dir = "/work/feeds"
+        backend = "sqlite"
+    "#;
+
+    #[test]
+    fn defaults_without_file_or_env() {
+        assert_eq!(load(None, &[]).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn file_then_env_override_defaults() {
+        let config = load(Some(r#"feed_dir = "/feeds""#), &[]).unwrap();
+        assert_eq!(config.feed_dir, Some(PathBuf::from("/feeds")));
+        assert_eq!(config.store, StoreOptions::default());
+
+        let config = load(Some("backend = \"sharded\"\nbackups = 2\n"), &[]).unwrap();
+        assert_eq!((config.backend, config.store.backups), (Backend::Sharded, 2));
+
+        let config = load(Some("backend = \"sharded\"\nbackups = 2\n"), &[
+            ("FEEDUTILS_BACKEND", "tsv"),
+            ("FEEDUTILS_CONFIGDIR", "/env/feeds"),
+            ("FEEDUTILS_DB", "/env/feedutils.tsv"),
+        ]).unwrap();
+        assert_eq!((config.backend, config.store.backups), (Backend::Tsv, 2));
+        assert_eq!(config.feed_dir, Some(PathBuf::from("/env/feeds")));
+        assert_eq!(config.database, Some(PathBuf::from("/env/feedutils.tsv")));
+    }
+
+    #[test]
+    fn profile_overrides_env() {
+        // The file's profile, with the defaults for its name
+        let env = [("FEEDUTILS_BACKEND", "tsv"), ("FEEDUTILS_CONFIGDIR", "/env/feeds"), ("FEEDUTILS_DB", "/env/feedutils.tsv")];
+        let config = load(Some(PROFILES), &env).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("personal"));
+        assert_eq!(config.feed_dir, default_feed_dir(Some("personal")));
+        assert_eq!((config.database, config.backend), (None, Backend::Tsv));
+
+        // FEEDUTILS_PROFILE picks another, whose settings beat the variables
+        let env = [("FEEDUTILS_PROFILE", "work"), env[0], env[1], env[2]];
+        let config = load(Some(PROFILES), &env).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("work"));
+        assert_eq!(config.feed_dir, Some(PathBuf::from("/work/feeds")));
+        assert_eq!((config.database, config.backend), (None, Backend::Sqlite));
+    }
+
+    #[test]
+    fn undeclared_profiles_use_defaults() {
+        let config = load(Some(PROFILES), &[("FEEDUTILS_PROFILE", "other")]).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("other"));
+        assert_eq!(config.feed_dir, default_feed_dir(Some("other")));
+        assert_eq!((config.database, config.backend), (None, Backend::Sharded));
+
+        let config = load(None, &[("FEEDUTILS_PROFILE", "other"), ("FEEDUTILS_DB", "/env/feedutils.tsv")]).unwrap();
+        assert_eq!(config.feed_dir, default_feed_dir(Some("other")));
+        assert_eq!(config.database, None);
+    }
+
+    #[test]
+    fn reads_settings() {
+        let config = load(Some(r#"
+            database = "/data/feedutils.tsv"
+            backups = 0
+            seen_days = 30
+            open = "xdg-open"
+            fetch = "curl -s"
+
+            [lock]
+            wait = "block"
+            method = "flock"
+
+            [update]
+            skip = ["slow", "broken"]
+            jobs = 8
+            per_host = 2
+            timeout = 0
+            max_size = 1024
+        "#), &[]).unwrap();
+        assert_eq!(config.database, Some(PathBuf::from("/data/feedutils.tsv")));
+        assert_eq!((config.store.backups, config.store.seen_days), (0, 30));
+        assert_eq!((config.open.as_deref(), config.fetch.as_deref()), (Some("xdg-open"), Some("curl -s")));
+        assert_eq!((config.store.lock.wait, config.store.lock.method), (LockWait::Block, LockMethod::Flock));
+        assert_eq!(config.update, UpdateOptions {
+            skip: vec!["slow".to_string(), "broken".to_string()],
+            jobs: 8,
+            per_host: Some(2),
+            timeout: None,
+            max_size: 1024,
+        });
+
+        let config = load(Some("lock = { wait = 0.5 }\nupdate = { timeout = 1.5 }\n"), &[]).unwrap();
+        assert_eq!(config.store.lock.wait, LockWait::Timeout(time::Duration::from_millis(500)));
+        assert_eq!(config.update.timeout, Some(time::Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn expands_home() {
+        let config = load(Some(r#"feed_dir = "~/feeds""#), &[]).unwrap();
+        let expected = match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join("feeds"),
+            None => PathBuf::from("~/feeds"),
+        };
+        assert_eq!(config.feed_dir, Some(expected));
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        for contents in ["feeds = \"/feeds\"", "[lock]\nretries = 3", "[profiles.work]\nopen = \"x\""] {
+            assert!(matches!(load(Some(contents), &[]), Err(ConfigError::UnknownSetting{ .. })), "{}", contents);
+        }
+        for contents in ["backups = -1", "backend = \"csv\"", "lock = { wait = \"never\" }", "update = { jobs = 0 }",
+                         "update = { per_host = 0 }", "update = { skip = \"slow\" }", "feed_dir = 1"] {
+            assert!(matches!(load(Some(contents), &[]), Err(ConfigError::InvalidSetting{ .. })), "{}", contents);
+        }
+        assert!(matches!(load(Some("backups ="), &[]), Err(ConfigError::ParseError{ .. })));
+    }
+
+    #[test]
+    fn reads_env_overrides() {
+        let config = load(Some("backups = 2\nseen_days = 30\n[lock]\nwait = \"block\"\n"), &[
+            ("FEEDUTILS_LOCK_WAIT", "fail"),
+            ("FEEDUTILS_LOCK_METHOD", "flock"),
+            ("FEEDUTILS_BACKUPS", "7"),
+            ("FEEDUTILS_SEEN_DAYS", "10"),
+        ]).unwrap();
+        assert_eq!((config.store.lock.wait, config.store.lock.method), (LockWait::FailFast, LockMethod::Flock));
+        assert_eq!((config.store.backups, config.store.seen_days), (7, 10));
+
+        for name in ["FEEDUTILS_BACKEND", "FEEDUTILS_LOCK_WAIT", "FEEDUTILS_LOCK_METHOD", "FEEDUTILS_BACKUPS", "FEEDUTILS_SEEN_DAYS"] {
+            match load(None, &[(name, "bogus")]) {
+                Err(ConfigError::InvalidSetting{ name: setting, value }) => assert_eq!((setting.as_str(), value.as_str()), (name, "bogus")),
+                _ => panic!("{} not rejected", name),
+            }
+        }
+    }
+}
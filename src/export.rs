@@ -0,0 +1,190 @@
+// Exporting entries in formats other programs can read.
+// The formats are simple enough to write by hand, which saves depending on
+// a serialisation library.
+
+use std::io;
+use std::io::Write;
+
+use crate::Entry;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExportFormat {
+    // A single JSON array of objects
+    Json,
+    // One JSON object per line
+    JsonLines,
+    // RFC 4180 CSV with a header row
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name {
+            "json" => Some(ExportFormat::Json),
+            "jsonl" => Some(ExportFormat::JsonLines),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+// Field names, in the order they are written.
+const EXPORT_FIELDS: [&str; 9] = ["feed", "id", "updated", "title", "link", "read", "starred", "read_at", "first_seen"];
+
+enum Value<'a> {
+    String(&'a str),
+    Bool(bool),
+    Null,
+}
+
+fn optional_value(value: &Option<String>) -> Value<'_> {
+    return value.as_deref().map(Value::String).unwrap_or(Value::Null);
+}
+
+fn entry_values(entry: &Entry) -> [Value<'_>; 9] {
+    return [
+        Value::String(&entry.feed),
+        Value::String(&entry.id),
+        Value::String(&entry.updated),
+        Value::String(&entry.title),
+        Value::String(&entry.link),
+        Value::Bool(entry.read),
+        Value::Bool(entry.starred),
+        optional_value(&entry.read_at),
+        optional_value(&entry.first_seen),
+    ];
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    return escaped;
+}
+
+fn json_object(entry: &Entry) -> String {
+    let mut members: Vec<String> = Vec::new();
+    for (name, value) in EXPORT_FIELDS.iter().zip(entry_values(entry)) {
+        let value = match value {
+            Value::String(value) => json_string(value),
+            Value::Bool(value) => value.to_string(),
+            Value::Null => "null".to_string(),
+        };
+        members.push(format!("{}:{}", json_string(name), value));
+    }
+    return format!("{{{}}}", members.join(","));
+}
+
+fn csv_field(value: &str) -> String {
+    // Quote fields containing delimiters, doubling any quotes.
+    if value.contains([',', '"', '\r', '\n']) {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value.to_string();
+}
+
+fn csv_record(entry: &Entry) -> String {
+    let mut fields: Vec<String> = Vec::new();
+    for value in entry_values(entry) {
+        fields.push(match value {
+            Value::String(value) => csv_field(value),
+            Value::Bool(value) => value.to_string(),
+            Value::Null => String::new(),
+        });
+    }
+    return fields.join(",");
+}
+
+pub fn export_entries<W: Write>(writer: &mut W, entries: &[Entry], format: ExportFormat) -> io::Result<()> {
+    // Write the entries in the given format. Unknown times are null in JSON,
+    // and empty in CSV.
+
+    match format {
+        ExportFormat::Json => {
+            writer.write_all(b"[")?;
+            for (index, entry) in entries.iter().enumerate() {
+                if index > 0 {
+                    writer.write_all(b",")?;
+                }
+                writer.write_all(b"\n")?;
+                writer.write_all(json_object(entry).as_bytes())?;
+            }
+            writer.write_all(b"\n]\n")?;
+        },
+        ExportFormat::JsonLines => {
+            for entry in entries {
+                writer.write_all((json_object(entry) + "\n").as_bytes())?;
+            }
+        },
+        ExportFormat::Csv => {
+            // RFC 4180 records end with CRLF
+            writer.write_all((EXPORT_FIELDS.join(",") + "\r\n").as_bytes())?;
+            for entry in entries {
+                writer.write_all((csv_record(entry) + "\r\n").as_bytes())?;
+            }
+        },
+    }
+    return writer.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(json_string("back\\slash"), "\"back\\\\slash\"");
+        assert_eq!(json_string("a,b\nc"), "\"a,b\\nc\"");
+        assert_eq!(json_string("\u{e9}\u{1f600}"), "\"\u{e9}\u{1f600}\"");
+    }
+
+    #[test]
+    fn escapes_json_control_characters() {
+        for c in '\u{0}'..='\u{1f}' {
+            let expected = match c {
+                '\n' => "\\n".to_string(),
+                '\r' => "\\r".to_string(),
+                '\t' => "\\t".to_string(),
+                c => format!("\\u{:04x}", c as u32),
+            };
+            assert_eq!(json_string(&c.to_string()), format!("\"{}\"", expected));
+        }
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(csv_field("back\\slash"), "back\\slash");
+    }
+
+    #[test]
+    fn keeps_csv_control_characters() {
+        // Only line breaks need quoting; other control characters are kept
+        // as they are
+        for c in '\u{0}'..='\u{1f}' {
+            let field = format!("a{}b", c);
+            let expected = match c {
+                '\r' | '\n' => format!("\"{}\"", field),
+                _ => field.clone(),
+            };
+            assert_eq!(csv_field(&field), expected);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::process::exit;
+
+use feedutil::{LockMethod, LockWait};
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    // Either all settings, as TOML, or the value of one setting, so that
+    // shell scripts can find the feed directory the same way as everything
+    // else
+    let setting = match args.len() {
+        1 => None,
+        2 => Some(args[1].clone()),
+        _ => {
+            eprintln!("usage: feed-config [<setting>]");
+            exit(1);
+        },
+    };
+
+    let database = match feedutil::get_database_path(&config, config.backend) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+    };
+
+    let string = |value: &str| toml::Value::String(value.to_string());
+    let lock_wait = match config.store.lock.wait {
+        LockWait::FailFast => string("fail"),
+        LockWait::Block => string("block"),
+        LockWait::Timeout(timeout) => toml::Value::Float(timeout.as_secs_f64()),
+    };
+    let lock_method = match config.store.lock.method {
+        LockMethod::LockFile => "lockfile",
+        LockMethod::Flock => "flock",
+    };
+    let settings: Vec<(&str, Option<toml::Value>)> = vec![
+        ("profile", config.profile.map(|profile| string(&profile))),
+        ("feed_dir", config.feed_dir.map(|path| string(&path.to_string_lossy()))),
+        ("database", Some(string(&database.to_string_lossy()))),
+        ("backend", Some(string(config.backend.name()))),
+        ("backups", Some(toml::Value::Integer(config.store.backups as i64))),
+        ("seen_days", Some(toml::Value::Integer(config.store.seen_days.into()))),
+        ("open", config.open.map(|open| string(&open))),
+        ("fetch", config.fetch.map(|fetch| string(&fetch))),
+        ("lock.wait", Some(lock_wait)),
+        ("lock.method", Some(string(lock_method))),
+        ("update.jobs", Some(toml::Value::Integer(config.update.jobs as i64))),
+        ("update.per_host", config.update.per_host.map(|per_host| toml::Value::Integer(per_host as i64))),
+        ("update.timeout", Some(toml::Value::Float(config.update.timeout.map(|timeout| timeout.as_secs_f64()).unwrap_or(0.0)))),
+        ("update.max_size", Some(toml::Value::Integer(config.update.max_size as i64))),
+        ("update.skip", Some(toml::Value::Array(config.update.skip.iter().map(|feed| string(feed)).collect()))),
+    ];
+
+    match setting {
+        None => {
+            for (name, value) in settings {
+                if let Some(value) = value {
+                    println!("{} = {}", name, value);
+                }
+            }
+        },
+        Some(setting) => {
+            let value = match settings.into_iter().find(|(name, _)| *name == setting) {
+                Some((_, value)) => value,
+                None => {
+                    eprintln!("Unknown setting: {}", setting);
+                    exit(1);
+                },
+            };
+            // Plain values, one per line for lists; unset settings print
+            // nothing
+            match value {
+                Some(toml::Value::String(value)) => println!("{}", value),
+                Some(toml::Value::Array(values)) => {
+                    for value in values {
+                        println!("{}", value.as_str().unwrap_or_default());
+                    }
+                },
+                Some(value) => println!("{}", value),
+                None => {},
+            }
+        },
+    }
+}
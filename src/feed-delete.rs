@@ -1,43 +1,31 @@
-use std::fs;
 use std::process::exit;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let (args, config) = feedutil::load_or_exit();
 
     if args.len() == 2 {
         let feed_name = args[1].clone();
 
-        let feed_dir = match feedutil::get_feed_dir(feed_name.clone()) {
-            Ok(feed_dir) => feed_dir,
-            Err(e) => {
-                eprintln!("{}: {}", e, feed_name.clone());
-                exit(1);
-            },
-        };
-
-        let database_path = match feedutil::get_database_path() {
-            Ok(path) => path,
-            Err(e) => {
-                eprintln!("{}", e);
-                exit(1);
-            },
-        };
+        if let Err(e) = feedutil::get_feed_dir(&config, feed_name.clone()) {
+            eprintln!("{}: {}", e, feed_name.clone());
+            exit(1);
+        }
 
-        let modifier = |entries: Vec<feedutil::Entry>| -> Vec<feedutil::Entry> {
-            let mut modified_entries: Vec<feedutil::Entry> = Vec::new();
-            for entry in entries {
-                if entry.feed != feed_name {
-                    modified_entries.push(entry);
-                }
-            }
-            return modified_entries;
+        // Forget the seen ids too, in case a feed of the same name is added
+        let modifier = |_: Vec<feedutil::Entry>, seen: &mut feedutil::SeenIds| -> Vec<feedutil::Entry> {
+            seen.remove_feed(&feed_name);
+            return Vec::new();
         };
-        if let Err(e) = feedutil::modify_database(modifier, database_path) {
+        let result = feedutil::open_store(&config)
+            .and_then(|store| store.modify_feed(&feed_name, Box::new(modifier)));
+        if let Err(e) = result {
             eprintln!("Failed to delete entries: {}", e);
             exit(1);
         }
 
-        if let Err(e) = fs::remove_dir_all(feed_dir) {
+        // The configuration is only moved aside, so that feed-undo can bring
+        // the feed back along with its entries
+        if let Err(e) = feedutil::delete_feed_dir(&config, &feed_name) {
             eprintln!("Failed to delete feed configuration: {}", e);
             exit(1);
         }
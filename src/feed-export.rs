@@ -0,0 +1,48 @@
+use std::io;
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("usage: feed-export [-f json|jsonl|csv] [-r read|unread] [<feed> ...]");
+    exit(1);
+}
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    let mut format = feedutil::ExportFormat::Json;
+    let mut read = None;
+    let mut feed_names: Vec<String> = Vec::new();
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" => {
+                format = match args.next().as_deref().and_then(feedutil::ExportFormat::from_name) {
+                    Some(format) => format,
+                    None => usage(),
+                };
+            },
+            "-r" => {
+                read = match args.next().as_deref() {
+                    Some("read") => Some(true),
+                    Some("unread") => Some(false),
+                    _ => usage(),
+                };
+            },
+            _ if arg.starts_with('-') => usage(),
+            _ => feed_names.push(arg),
+        }
+    }
+
+    let entries = match feedutil::get_entries(&config, &feed_names, read) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+    };
+    let mut stdout = io::stdout().lock();
+    if let Err(e) = feedutil::export_entries(&mut stdout, &entries, format) {
+        eprintln!("Failed to write entries: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::process::exit;
+
+use chrono::DateTime;
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    let repaired_path = match args.len() {
+        1 => None,
+        3 if args[1] == "-o" => Some(PathBuf::from(&args[2])),
+        _ => {
+            eprintln!("usage: feed-fsck [-o <repaired>]");
+            exit(1);
+        }
+    };
+
+    if config.backend != feedutil::Backend::Tsv {
+        eprintln!("Only TSV databases can be checked, not {}", config.backend.name());
+        exit(1);
+    }
+    let database_path = match feedutil::get_database_path(&config, feedutil::Backend::Tsv) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+    };
+
+    let database = match feedutil::read_tsv_database_lenient(database_path) {
+        Ok(database) => database,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+    };
+
+    let mut problems = 0;
+    for problem in database.problems.iter() {
+        println!("{}", problem.error);
+        problems += 1;
+    }
+
+    // Keep the first of any duplicated entries, merged as when indexing them
+    // Map of (feed, id) to the first line and position in repaired_entries
+    let mut first_seen: HashMap<(String, String), (usize, usize)> = HashMap::new();
+    let mut repaired_entries: Vec<feedutil::Entry> = Vec::new();
+    for (line, entry) in database.rows.iter() {
+        if DateTime::parse_from_rfc3339(&entry.updated).is_err() {
+            println!("Invalid updated date {} on line {}", entry.updated, line);
+            problems += 1;
+        }
+        for (name, time) in [("read_at", &entry.read_at), ("first_seen", &entry.first_seen)] {
+            if let Some(time) = time {
+                if DateTime::parse_from_rfc3339(time).is_err() {
+                    println!("Invalid {} date {} on line {}", name, time, line);
+                    problems += 1;
+                }
+            }
+        }
+
+        let key = (entry.feed.clone(), entry.id.clone());
+        if let Some((first_line, position)) = first_seen.get(&key) {
+            println!("Duplicate of line {} on line {}: {} {}", first_line, line, entry.feed, entry.id);
+            problems += 1;
+            repaired_entries[*position].merge_duplicate(entry);
+            continue;
+        }
+        first_seen.insert(key, (*line, repaired_entries.len()));
+        repaired_entries.push(entry.clone());
+    }
+
+    // Entries for feeds which no longer exist are never updated or read
+    match feedutil::get_all_feed_names(&config) {
+        Ok(feeds) => {
+            let feeds: HashSet<String> = feeds.into_iter().collect();
+            let mut orphans: HashMap<String, u32> = HashMap::new();
+            for entry in repaired_entries.iter() {
+                if !feeds.contains(&entry.feed) {
+                    *orphans.entry(entry.feed.clone()).or_insert(0) += 1;
+                }
+            }
+            let mut orphans = Vec::from_iter(orphans);
+            orphans.sort();
+            for (feed_name, count) in orphans {
+                println!("{} entries for feed {} which does not exist", count, feed_name);
+                problems += 1;
+            }
+            repaired_entries.retain(|entry| feeds.contains(&entry.feed));
+        },
+        Err(e) => {
+            eprintln!("Cannot list feeds, not checking for orphaned entries: {}", e);
+        },
+    }
+
+    if let Some(repaired_path) = repaired_path {
+        if let Err(e) = feedutil::write_tsv_database(repaired_path.clone(), &repaired_entries) {
+            eprintln!("Failed to write repaired database: {}: {}", e, repaired_path.display());
+            exit(1);
+        }
+        println!("Wrote {} entries to {}", repaired_entries.len(), repaired_path.display());
+    }
+
+    if problems > 0 {
+        println!("{} problems found", problems);
+        exit(1);
+    }
+}
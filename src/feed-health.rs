@@ -0,0 +1,57 @@
+use std::process::exit;
+
+use chrono::{DateTime, Local, Utc};
+
+fn format_time(time: DateTime<Utc>) -> String {
+    return time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string();
+}
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+    if args.get(1).is_some_and(|arg| arg.starts_with('-')) {
+        eprintln!("usage: feed-health [<feed> ...]");
+        exit(1);
+    }
+
+    // Without arguments, list just the failing feeds
+    let feeds = if args.len() == 1 {
+        match feedutil::get_all_feed_names(&config) {
+            Ok(feeds) => feeds,
+            Err(e) => {
+                eprintln!("Cannot list feeds: {}", e);
+                exit(1);
+            },
+        }
+    } else {
+        args[1..].to_vec()
+    };
+    let mut records = Vec::new();
+    for feed_name in feeds {
+        match feedutil::read_health(&config, &feed_name) {
+            Ok(health) if args.len() > 1 || health.failures > 0 => records.push((feed_name, health)),
+            Ok(_) => {},
+            Err(e) => {
+                eprintln!("{}: {}", feed_name, e);
+                exit(1);
+            },
+        }
+    }
+
+    // Feeds that have been failing the longest (or never worked) first
+    records.sort_by(|a, b| (a.1.failures == 0, a.1.last_success, &a.0).cmp(&(b.1.failures == 0, b.1.last_success, &b.0)));
+    for (feed_name, health) in records {
+        match (health.failures, health.last_attempt, health.last_success) {
+            (_, None, _) => println!("{}: never updated", feed_name),
+            (0, Some(last_attempt), _) => println!("{}: ok, last updated {}", feed_name, format_time(last_attempt)),
+            (failures, Some(_), Some(last_success)) => {
+                println!("{}: failing since {} ({} failed updates)", feed_name, format_time(last_success), failures);
+            },
+            (failures, Some(_), None) => println!("{}: failing, never updated ({} failed updates)", feed_name, failures),
+        }
+        if health.failures > 0 {
+            for line in health.last_error.unwrap_or_default().lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+}
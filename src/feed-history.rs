@@ -0,0 +1,45 @@
+use std::process::exit;
+
+use chrono::{DateTime, Duration, Local, Utc};
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    // Entries read within the last few days, by default just today and
+    // yesterday
+    let days: i64 = match args.len() {
+        1 => 2,
+        2 => match args[1].parse() {
+            Ok(days) if days > 0 => days,
+            _ => {
+                eprintln!("usage: feed-history [<days>]");
+                exit(1);
+            },
+        },
+        _ => {
+            eprintln!("usage: feed-history [<days>]");
+            exit(1);
+        },
+    };
+
+    let entries = match feedutil::get_read_entries(&config) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+    };
+
+    let cutoff = Utc::now() - Duration::days(days);
+    for (read_at, entry) in entries {
+        if read_at < cutoff {
+            break;
+        }
+        // Titles may contain tabs and newlines, which would break the layout
+        let title = entry.title.split_whitespace().collect::<Vec<&str>>().join(" ");
+        let read_at: DateTime<Local> = read_at.with_timezone(&Local);
+        println!("{} {}", read_at.format("%Y-%m-%d %H:%M"), entry.feed);
+        println!("    {}", title);
+        println!("    {}", entry.link);
+    }
+}
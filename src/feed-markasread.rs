@@ -1,39 +1,53 @@
 use std::process::exit;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let (args, config) = feedutil::load_or_exit();
 
     if args.len() == 2 {
         let feed_name = args[1].clone();
-        if let Err(e) = feedutil::get_feed_dir(feed_name.clone()) {
+        if let Err(e) = feedutil::get_feed_dir(&config, feed_name.clone()) {
             eprintln!("{}: {}", e, feed_name.clone());
             exit(1);
         }
 
-        let database_path = match feedutil::get_database_path() {
-            Ok(path) => path,
-            Err(e) => {
-                eprintln!("{}", e);
-                exit(1);
-            },
-        };
-
-        let modifier = |entries: Vec<feedutil::Entry>| -> Vec<feedutil::Entry> {
+        let modifier = |entries: Vec<feedutil::Entry>, _: &mut feedutil::SeenIds| -> Vec<feedutil::Entry> {
             let mut modified_entries: Vec<feedutil::Entry> = Vec::new();
             for mut entry in entries {
-                if entry.feed == feed_name {
-                    entry.read = true;
-                }
+                entry.set_read(true);
                 modified_entries.push(entry);
             }
             return modified_entries;
         };
-        if let Err(e) = feedutil::modify_database(modifier, database_path) {
+        let result = feedutil::open_store(&config)
+            .and_then(|store| store.modify_feed(&feed_name, Box::new(modifier)));
+        if let Err(e) = result {
             eprintln!("Failed to mark {} as read: {}", feed_name.clone(), e);
             exit(1);
         }
+    } else if args.len() > 2 {
+        // Mark only the given entries
+        let feed_name = args[1].clone();
+        let changes = args.into_iter().skip(2).map(|id| feedutil::ReadStateChange {
+            feed: feed_name.clone(),
+            id,
+            read: true,
+        }).collect();
+
+        let missing = match feedutil::set_read_states(&config, changes) {
+            Ok(missing) => missing,
+            Err(e) => {
+                eprintln!("Failed to mark entries as read: {}", e);
+                exit(1);
+            },
+        };
+        for change in missing.iter() {
+            eprintln!("No such entry: {}: {}", change.feed, change.id);
+        }
+        if !missing.is_empty() {
+            exit(1);
+        }
     } else {
-        eprintln!("usage: feed-markasread <feed>");
+        eprintln!("usage: feed-markasread <feed> [<id> ...]");
         exit(1);
     }
 }
@@ -0,0 +1,15 @@
+use std::process::exit;
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    if args.len() == 3 {
+        if let Err(e) = feedutil::merge_feeds(&config, &args[1], &args[2]) {
+            eprintln!("Failed to merge {} into {}: {}", args[1], args[2], e);
+            exit(1);
+        }
+    } else {
+        eprintln!("usage: feed-merge <feed> <into feed>");
+        exit(1);
+    }
+}
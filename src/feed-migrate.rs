@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+use std::process::exit;
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    if args.len() == 2 || args.len() == 3 {
+        let backend = match feedutil::Backend::from_name(&args[1]) {
+            Ok(backend) => backend,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            },
+        };
+        let destination_path = if args.len() == 3 {
+            PathBuf::from(&args[2])
+        } else {
+            match feedutil::get_database_path(&config, backend) {
+                Ok(path) => path,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                },
+            }
+        };
+
+        // Refuse to overwrite anything, including the source database
+        if destination_path.exists() {
+            eprintln!("Destination database already exists: {}", destination_path.display());
+            exit(1);
+        }
+
+        let source = feedutil::open_store(&config);
+        let contents = source.and_then(|store| Ok((store.entries()?, store.seen_ids()?)));
+        let (entries, seen) = match contents {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            },
+        };
+        let count = entries.len();
+
+        let destination = match feedutil::open_backend_store(&config, backend, destination_path.clone()) {
+            Ok(store) => store,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            },
+        };
+        let result = destination.initialize()
+            .and_then(|_| destination.modify_seen(Box::new(|_, destination_seen| {
+                *destination_seen = seen;
+                return entries;
+            })));
+        if let Err(e) = result {
+            eprintln!("Failed to migrate entries: {}", e);
+            exit(1);
+        }
+
+        println!("Migrated {} entries to {}", count, destination_path.display());
+        println!("Set backend = \"{}\" in feedutils.toml to use the new database", backend.name());
+    } else {
+        eprintln!("usage: feed-migrate <tsv|sqlite|sharded> [<path>]");
+        exit(1);
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::process::exit;
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    if args.len() == 2 && args[1] == "export" {
+        let feed_names = match feedutil::get_all_feed_names(&config) {
+            Ok(feed_names) => feed_names,
+            Err(e) => {
+                eprintln!("Cannot list feeds: {}", e);
+                exit(1);
+            },
+        };
+        let mut subscriptions = Vec::new();
+        for feed_name in feed_names {
+            match feedutil::read_subscription(&config, &feed_name) {
+                Ok(subscription) if subscription.url.is_none() => {
+                    eprintln!("Skipping feed without a URL: {}", feed_name);
+                },
+                Ok(subscription) => subscriptions.push(subscription),
+                Err(e) => {
+                    eprintln!("{}: {}", e, feed_name);
+                    exit(1);
+                },
+            }
+        }
+        if let Err(e) = feedutil::write_opml(io::stdout().lock(), &subscriptions) {
+            eprintln!("{}", e);
+            exit(1);
+        }
+        println!();
+    } else if args.len() == 3 && args[1] == "import" {
+        let f = match fs::File::open(&args[2]) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("{}: {}", e, args[2]);
+                exit(1);
+            },
+        };
+        let subscriptions = match feedutil::parse_opml(io::BufReader::new(f)) {
+            Ok(subscriptions) => subscriptions,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            },
+        };
+
+        // Feeds already subscribed to, by URL. These are skipped, so that an
+        // import can be repeated after adding more to the OPML file.
+        let feed_names = match feedutil::get_all_feed_names(&config) {
+            Ok(feed_names) => feed_names,
+            Err(e) => {
+                eprintln!("Cannot list feeds: {}", e);
+                exit(1);
+            },
+        };
+        let mut subscribed: HashMap<String, String> = HashMap::new();
+        for feed_name in feed_names {
+            match feedutil::read_subscription(&config, &feed_name) {
+                Ok(subscription) => {
+                    if let Some(url) = subscription.url {
+                        subscribed.insert(url, feed_name);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("{}: {}", e, feed_name);
+                    exit(1);
+                },
+            }
+        }
+
+        let mut ok = true;
+        for mut subscription in subscriptions {
+            let url = subscription.url.clone().unwrap_or_default();
+            if subscription.name.is_empty() {
+                eprintln!("Skipping feed without a usable name: {}", url);
+                ok = false;
+                continue;
+            }
+            if let Some(feed_name) = subscribed.get(&url) {
+                println!("Already subscribed to {} as {}", url, feed_name);
+                continue;
+            }
+
+            // A different feed with the same name is told apart by its host
+            let mut result = feedutil::add_subscription(&config, &subscription);
+            if let (Err(feedutil::OpmlError::FeedExists{ .. }), Some(name)) = (&result, feedutil::name_with_host(&subscription)) {
+                subscription.name = name;
+                result = feedutil::add_subscription(&config, &subscription);
+            }
+            match result {
+                Ok(_) => {
+                    println!("Added feed {}", subscription.name);
+                    subscribed.insert(url, subscription.name);
+                },
+                Err(feedutil::OpmlError::FeedExists{ name }) => {
+                    eprintln!("Not adding {}: feed {} already exists with a different URL", url, name);
+                    ok = false;
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    ok = false;
+                },
+            }
+        }
+        if !ok {
+            exit(1);
+        }
+    } else {
+        eprintln!("usage: feed-opml export");
+        eprintln!("       feed-opml import <file>");
+        exit(1);
+    }
+}
@@ -1,27 +1,32 @@
 use std::process::exit;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let (args, config) = feedutil::load_or_exit();
 
     if args.len() >= 2 {
         let mut ok = true;
+        let mut feed_names: Vec<String> = Vec::new();
         for feed_name in args.into_iter().skip(1) {
-            if let Err(e) = feedutil::get_feed_dir(feed_name.clone()) {
+            if let Err(e) = feedutil::get_feed_dir(&config, feed_name.clone()) {
                 eprintln!("{}: {}", e, feed_name.clone());
                 ok = false;
                 continue;
             }
+            feed_names.push(feed_name);
+        }
 
-            let entries = match feedutil::get_feed_entries(feed_name.clone()) {
-                Ok(entries) => entries,
-                Err(e) => {
-                    eprintln!("{}", e);
-                    exit(1);
-                },
-            };
-            for entry in entries {
+        let index = match feedutil::load_index(&config, &feed_names) {
+            Ok(index) => index,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            },
+        };
+        for feed_name in feed_names.iter() {
+            // Oldest first
+            for entry in index.feed_entries(feed_name) {
                 if !entry.read {
-                    if let Err(e) = feedutil::read_entry(feed_name.clone(), entry) {
+                    if let Err(e) = feedutil::read_entry(&config, feed_name.clone(), entry.clone()) {
                         eprintln!("{}", e);
                         ok = false;
                     }
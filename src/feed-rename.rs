@@ -0,0 +1,15 @@
+use std::process::exit;
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    if args.len() == 3 {
+        if let Err(e) = feedutil::rename_feed(&config, &args[1], &args[2]) {
+            eprintln!("Failed to rename {}: {}", args[1], e);
+            exit(1);
+        }
+    } else {
+        eprintln!("usage: feed-rename <feed> <new name>");
+        exit(1);
+    }
+}
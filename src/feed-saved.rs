@@ -0,0 +1,24 @@
+use std::process::exit;
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+    // Optionally limited to the given feeds
+    let feed_names: Vec<String> = args.into_iter().skip(1).collect();
+
+    let index = match feedutil::load_index(&config, &feed_names) {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+    };
+    for feed_name in index.feeds() {
+        for entry in index.feed_entries(feed_name).filter(|entry| entry.starred) {
+            // Titles may contain tabs and newlines, which would break the layout
+            let title = entry.title.split_whitespace().collect::<Vec<&str>>().join(" ");
+            println!("{} {}", entry.feed, entry.id);
+            println!("    {}", title);
+            println!("    {}", entry.link);
+        }
+    }
+}
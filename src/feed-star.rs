@@ -0,0 +1,27 @@
+use std::process::exit;
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    if args.len() > 2 {
+        let feed_name = args[1].clone();
+        let entry_ids: Vec<String> = args.into_iter().skip(2).collect();
+
+        let missing = match feedutil::set_starred(&config, &feed_name, entry_ids, true) {
+            Ok(missing) => missing,
+            Err(e) => {
+                eprintln!("Failed to star entries: {}", e);
+                exit(1);
+            },
+        };
+        for entry_id in missing.iter() {
+            eprintln!("No such entry: {}: {}", feed_name, entry_id);
+        }
+        if !missing.is_empty() {
+            exit(1);
+        }
+    } else {
+        eprintln!("usage: feed-star <feed> <id> [<id> ...]");
+        exit(1);
+    }
+}
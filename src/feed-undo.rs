@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::process::exit;
+
+#[derive(Default)]
+struct FeedChanges {
+    restored: u32,
+    removed: u32,
+    marked_read: u32,
+    marked_unread: u32,
+    starred: u32,
+    unstarred: u32,
+    changed: u32,
+    // The feed has been deleted, and its configuration can be restored
+    configuration_restored: bool,
+}
+
+fn describe(changes: &FeedChanges) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    for (count, description) in [
+        (changes.restored, "restored"),
+        (changes.removed, "removed"),
+        (changes.marked_read, "marked read"),
+        (changes.marked_unread, "marked unread"),
+        (changes.starred, "starred"),
+        (changes.unstarred, "unstarred"),
+        (changes.changed, "changed"),
+    ] {
+        if count > 0 {
+            parts.push(format!("{} {}", count, description));
+        }
+    }
+    if changes.configuration_restored {
+        parts.push("configuration restored".to_string());
+    }
+    return parts.join(", ");
+}
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    // -n only shows what would change
+    let dry_run = args.len() > 1 && args[1] == "-n";
+    let rest = if dry_run { &args[2..] } else { &args[1..] };
+    let number: usize = match rest {
+        [] => 1,
+        [number] => match number.parse() {
+            Ok(number) if number > 0 => number,
+            _ => {
+                eprintln!("usage: feed-undo [-n] [<count>]");
+                exit(1);
+            },
+        },
+        _ => {
+            eprintln!("usage: feed-undo [-n] [<count>]");
+            exit(1);
+        },
+    };
+
+    let store = match feedutil::open_store(&config) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+    };
+
+    let backup_entries = match store.read_backup(number) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Cannot read backup {}: {}", number, e);
+            exit(1);
+        },
+    };
+    let entries = match store.entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+    };
+
+    // Summarise the difference per feed, as going from the current entries
+    // to the backup
+    let mut changes: BTreeMap<String, FeedChanges> = BTreeMap::new();
+    let current = feedutil::EntryIndex::new(entries);
+    let backup = feedutil::EntryIndex::new(backup_entries.clone());
+    for entry in backup.iter() {
+        let feed_changes = changes.entry(entry.feed.clone()).or_default();
+        match current.get(&entry.feed, &entry.id) {
+            None => feed_changes.restored += 1,
+            Some(current) => {
+                if current.read != entry.read {
+                    if entry.read {
+                        feed_changes.marked_read += 1;
+                    } else {
+                        feed_changes.marked_unread += 1;
+                    }
+                }
+                if current.starred != entry.starred {
+                    if entry.starred {
+                        feed_changes.starred += 1;
+                    } else {
+                        feed_changes.unstarred += 1;
+                    }
+                }
+                if current.title != entry.title || current.updated != entry.updated || current.link != entry.link {
+                    feed_changes.changed += 1;
+                }
+            },
+        }
+    }
+    for entry in current.iter() {
+        if !backup.contains(&entry.feed, &entry.id) {
+            changes.entry(entry.feed.clone()).or_default().removed += 1;
+        }
+    }
+
+    // Entries restored for a deleted feed need its configuration back too, or
+    // the feed won't be updated
+    for (feed_name, feed_changes) in changes.iter_mut() {
+        if feed_changes.restored == 0 {
+            continue;
+        }
+        if let Err(feedutil::FeedDirError::FeedDoesNotExist) = feedutil::get_feed_dir(&config, feed_name.clone()) {
+            if feedutil::get_deleted_feed_dir(&config, feed_name).is_ok() {
+                feed_changes.configuration_restored = true;
+            } else {
+                eprintln!("{}: feed configuration is missing, so its entries won't be updated", feed_name);
+            }
+        }
+    }
+
+    let mut changed = false;
+    for (feed_name, feed_changes) in changes.iter() {
+        let description = describe(feed_changes);
+        if !description.is_empty() {
+            println!("{}: {}", feed_name, description);
+            changed = true;
+        }
+    }
+    if !changed {
+        println!("No changes");
+        return;
+    }
+    if dry_run {
+        return;
+    }
+
+    // Restoring is itself a modification, so it can be undone in turn
+    if let Err(e) = store.modify(Box::new(|_| backup_entries)) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+    let mut failed = false;
+    for (feed_name, _) in changes.iter().filter(|(_, feed_changes)| feed_changes.configuration_restored) {
+        if let Err(e) = feedutil::restore_feed_dir(&config, feed_name) {
+            eprintln!("Failed to restore feed configuration: {}: {}", e, feed_name);
+            failed = true;
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
@@ -0,0 +1,84 @@
+use std::process::exit;
+
+fn main() {
+    let (args, config) = feedutil::load_or_exit();
+
+    let force = match args.len() {
+        1 => false,
+        2 if args[1] == "-f" => true,
+        _ => {
+            eprintln!("usage: feed-unlock [-f]");
+            exit(1);
+        }
+    };
+
+    let store = match feedutil::open_store(&config) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        },
+    };
+
+    let mut locked = false;
+    let mut ok = true;
+    for lockfile_path in store.lockfiles() {
+        let state = match feedutil::get_lock_state(&lockfile_path) {
+            Ok(state) => state,
+            Err(e) => {
+                eprintln!("Cannot read lockfile: {}: {}", e, lockfile_path.display());
+                ok = false;
+                continue;
+            },
+        };
+
+        // Only break locks which are provably stale, unless forced
+        let stale = match &state {
+            feedutil::LockState::Unlocked => continue,
+            feedutil::LockState::Owned(owner) => {
+                println!("{}: locked by process {} on {} since {}",
+                         lockfile_path.display(), owner.pid, owner.host, owner.time);
+                match owner.is_alive() {
+                    Some(true) => {
+                        println!("Process {} is still running", owner.pid);
+                        false
+                    },
+                    Some(false) => true,
+                    None => {
+                        println!("Cannot tell whether process {} is still running", owner.pid);
+                        false
+                    },
+                }
+            },
+            feedutil::LockState::Unknown => {
+                println!("{}: locked by an unknown process", lockfile_path.display());
+                false
+            },
+        };
+        locked = true;
+
+        if !stale && !force {
+            eprintln!("Not removing lock; use -f to remove it anyway");
+            ok = false;
+            continue;
+        }
+        match feedutil::break_lock(&lockfile_path, &state) {
+            Ok(true) => println!("Removed lock"),
+            Ok(false) => {
+                eprintln!("Lock changed while removing it, try again");
+                ok = false;
+            },
+            Err(e) => {
+                eprintln!("Failed to remove lock: {}: {}", e, lockfile_path.display());
+                ok = false;
+            },
+        }
+    }
+
+    if !locked {
+        println!("Database is not locked");
+    }
+    if !ok {
+        exit(1);
+    }
+}
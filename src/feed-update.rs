@@ -1,37 +1,49 @@
 use std::process::exit;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let (args, config) = feedutil::load_or_exit();
+    let mut options = config.update.clone();
 
-    let feeds = match args.len() {
-        1 => {
-            match feedutil::get_all_feed_names() {
-                Ok(feeds) => feeds,
-                Err(e) => {
-                    eprintln!("Cannot list feeds: {}", e);
-                    exit(1);
-                }
+    // -j overrides the configured number of feeds to fetch at once
+    let mut rest = &args[1..];
+    if rest.len() >= 2 && rest[0] == "-j" {
+        options.jobs = match rest[1].parse() {
+            Ok(jobs) if jobs > 0 => jobs,
+            _ => {
+                eprintln!("usage: feed-update [-j <jobs>] [<feed> ...]");
+                exit(1);
+            }
+        };
+        rest = &rest[2..];
+    }
+    if rest.first().is_some_and(|arg| arg.starts_with('-')) {
+        eprintln!("usage: feed-update [-j <jobs>] [<feed> ...]");
+        exit(1);
+    }
+
+    let feeds = if rest.is_empty() {
+        match feedutil::get_all_feed_names(&config) {
+            Ok(feeds) => feeds.into_iter().filter(|feed| !options.skip.contains(feed)).collect(),
+            Err(e) => {
+                eprintln!("Cannot list feeds: {}", e);
+                exit(1);
             }
-        },
-        2 => vec![args[1].clone()],
-        _ => {
-            eprintln!("usage: feed-update [<feed>]");
-            exit(1);
         }
+    } else {
+        rest.to_vec()
     };
 
+    // Results are printed as each feed finishes, which may not be in order
     let mut ok = true;
-    for feed_name in feeds {
-        println!("Updating feed {}", feed_name);
-        let res = feedutil::update(feed_name);
-        match res {
+    feedutil::update_feeds(&config, feeds, &options, |feed_name, result| {
+        match result {
+            Ok(()) => println!("Updated feed {}", feed_name),
             Err(e) => {
-                eprintln!("{}", e);
+                eprintln!("Failed to update feed {}: {}", feed_name, e);
                 ok = false;
-            },
-            _ => {}
+            }
         }
-    }
+    });
     if !ok {
         exit(1);
     }
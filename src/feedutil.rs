@@ -1,23 +1,57 @@
 extern crate url;
 extern crate xml;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::io;
-use std::io::{BufWriter, Write, BufReader, BufRead};
+use std::io::{BufWriter, Write, BufReader, BufRead, Read, Seek, SeekFrom};
 use std::fs;
 use std::fs::OpenOptions;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
-use std::thread;
-use std::time;
+use std::time::Duration;
 
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
 use thiserror::Error;
 use url::Url;
 use xml::reader::{EventReader, XmlEvent};
 
-const ENTRY_DATABASE_HEADER: &str = "feed\tid\tupdated\ttitle\tlink\tread\n";
+mod config;
+mod export;
+mod fetch;
+mod health;
+mod http;
+mod index;
+mod lock;
+mod opml;
+mod scheduler;
+mod sharded;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod validators;
+
+pub use config::{get_args, get_config_path, load_or_exit, Config, ConfigError, Profile, UpdateOptions};
+pub use export::{export_entries, ExportFormat};
+pub use health::{read_health, record_update, FeedHealth, HealthError};
+pub use index::EntryIndex;
+pub use opml::{add_subscription, name_with_host, parse_opml, read_subscription, write_opml, OpmlError, Subscription};
+pub use lock::{break_lock, get_lock_state, LockMethod, LockOptions, LockOwner, LockState, LockWait};
+pub use scheduler::update_feeds;
+pub use sharded::ShardedStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+pub use validators::Validators;
+
+// Version of the database format written by write_entries. Older databases
+// are upgraded by the migrations in MIGRATIONS.
+const DATABASE_VERSION: u32 = 5;
+
+// Columns written by write_entries, in order.
+const DATABASE_COLUMNS: [&str; 9] = ["feed", "id", "updated", "title", "link", "read", "starred", "read_at", "first_seen"];
+
+// Once the journal of read state changes grows past this many bytes it is
+// folded back into the database.
+const JOURNAL_COMPACT_SIZE: u64 = 64 * 1024;
 
 #[derive(Clone, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Entry {
@@ -27,18 +61,56 @@ pub struct Entry {
     pub updated: String,
     pub link: String,
     pub read: bool,
+    // Starred entries are kept for later, even once read
+    pub starred: bool,
+    // When the entry was read, if it is and that is known
+    pub read_at: Option<String>,
+    // When the entry was first added from its feed, if known
+    pub first_seen: Option<String>,
+}
+
+impl Entry {
+    pub fn set_read(&mut self, read: bool) {
+        // Mark as read or unread, recording when it was read.
+        self.set_read_at(read, Some(Utc::now().to_rfc3339()));
+    }
+
+    fn set_read_at(&mut self, read: bool, time: Option<String>) {
+        // An entry which is already read keeps its original read time.
+        if !read {
+            self.read_at = None;
+        } else if !self.read {
+            self.read_at = time;
+        }
+        self.read = read;
+    }
+
+    pub fn merge_duplicate(&mut self, duplicate: &Entry) {
+        // Fold in a duplicate of this entry, from another feed or a damaged
+        // database, which counts as read (or starred) if either is. The
+        // earliest times are kept.
+        if duplicate.read && (!self.read || self.read_at.is_none() || duplicate.read_at < self.read_at) {
+            self.read_at = duplicate.read_at.clone().or(self.read_at.take());
+        }
+        self.read = self.read || duplicate.read;
+        self.starred = self.starred || duplicate.starred;
+        if duplicate.first_seen.is_some() && (self.first_seen.is_none() || duplicate.first_seen < self.first_seen) {
+            self.first_seen = duplicate.first_seen.clone();
+        }
+    }
 }
 
 fn sanitize(data: String) -> String {
     // Remove control characters - this should prevent the worst issues when
     // trying to deal with the resulting data stream.
+    // Tabs and newlines are kept, since the database escapes them.
     // Note that this removes *characters*, not *bytes*, so I'm assuming that
     // the code reading the result handles UTF-8 properly.
 
     let mut sanitized_data = String::new();
 
     for c in data.chars() {
-        if !c.is_control() {
+        if !c.is_control() || c == '\t' || c == '\n' {
             sanitized_data.push(c);
         }
     }
@@ -74,7 +146,7 @@ fn handle_rss_pub_date(pub_date: Option<String>) -> String {
     }
 }
 
-fn parse_rss<R: std::io::Read>(parser: xml::reader::Events<R>, feed: &String) -> Vec<Entry> {
+fn parse_rss<R: std::io::Read>(parser: xml::reader::Events<R>, feed: &str) -> Vec<Entry> {
     // Turn an RSS-like XML feed into a vector of entries
     // Data is attempted to be sanitized
 
@@ -126,12 +198,15 @@ fn parse_rss<R: std::io::Read>(parser: xml::reader::Events<R>, feed: &String) ->
                         }
 
                         let entry = Entry {
-                            feed: feed.clone(),
+                            feed: feed.to_string(),
                             id: id.take().unwrap(),
                             title: title.take().unwrap(),
                             updated: handle_rss_pub_date(pub_date.take()),
                             link: link.take().unwrap(),
                             read: false,
+                            starred: false,
+                            read_at: None,
+                            first_seen: None,
                         };
                         entries.push(entry);
                     }
@@ -154,7 +229,7 @@ fn parse_rss<R: std::io::Read>(parser: xml::reader::Events<R>, feed: &String) ->
     return entries;
 }
 
-fn parse_atom<R: std::io::Read>(parser: xml::reader::Events<R>, feed: &String) -> Vec<Entry> {
+fn parse_atom<R: std::io::Read>(parser: xml::reader::Events<R>, feed: &str) -> Vec<Entry> {
     // Turn an Atom-like XML feed into a vector of entries
     // Data is attempted to be sanitized
 
@@ -213,12 +288,15 @@ fn parse_atom<R: std::io::Read>(parser: xml::reader::Events<R>, feed: &String) -
                             eprintln!("Ignoring incomplete entry, missing link field");
                         } else {
                             let entry = Entry {
-                                feed: feed.clone(),
+                                feed: feed.to_string(),
                                 id: id.take().unwrap(),
                                 title: title.take().unwrap(),
                                 updated: updated.take().unwrap(),
                                 link: link.take().unwrap(),
                                 read: false,
+                                starred: false,
+                                read_at: None,
+                                first_seen: None,
                             };
                             entries.push(entry);
                         }
@@ -242,7 +320,7 @@ fn parse_atom<R: std::io::Read>(parser: xml::reader::Events<R>, feed: &String) -
     return entries;
 }
 
-fn parse_feed<R: std::io::Read>(reader: R, feed: &String) -> Vec<Entry> {
+fn parse_feed<R: std::io::Read>(reader: R, feed: &str) -> Vec<Entry> {
     // Turn an XML feed into a vector of entries.
     // Format is attempted to be autodetected, either Atom or RSS.
     // Data is attempted to be sanitized.
@@ -272,36 +350,6 @@ fn parse_feed<R: std::io::Read>(reader: R, feed: &String) -> Vec<Entry> {
     return Vec::new();
 }
 
-fn open_lockfile(filename: PathBuf) -> io::Result<fs::File> {
-    // Attempt to acquire a lockfile
-    // Will block until it exists, or the attempt times out
-
-    let mut delay = time::Duration::from_millis(50);
-    let timeout = time::Duration::from_millis(2000); // 2s seems more than long enough
-
-    loop {
-        let result = OpenOptions::new().write(true)
-                                       .create_new(true)
-                                       .open(filename.clone());
-        match result {
-            Ok(f) => return Ok(f),
-            Err(e) => {
-                if e.kind() != io::ErrorKind::AlreadyExists {
-                    return Err(e);
-                } else if delay >= timeout {
-                    // Timed out - since delays double each time, so far we've
-                    // waited just under `delay` time (using triangle formula).
-                    return Err(e);
-                } else {
-                    thread::sleep(delay);
-                    delay *= 2;
-                }
-            }
-        }
-
-    }
-}
-
 #[derive(Error, Debug)]
 pub enum DatabaseReadError {
     #[error("{source}: {path}")]
@@ -309,56 +357,596 @@ pub enum DatabaseReadError {
         source: io::Error,
         path: PathBuf,
     },
-    #[error("Missing {field} field, ignoring entry")]
+    #[error("Missing {field} field on line {line}")]
     MissingField {
         field: String,
+        line: usize,
+    },
+    #[error("Unknown read state {value} on line {line}")]
+    InvalidReadState {
+        value: String,
+        line: usize,
+    },
+    #[error("Unknown starred state {value} on line {line}")]
+    InvalidStarredState {
+        value: String,
+        line: usize,
+    },
+    #[error("Database header is missing the {column} column")]
+    MissingColumn {
+        column: String,
+    },
+    #[error("Invalid database {key}: {value}")]
+    InvalidMetadata {
+        key: String,
+        value: String,
     },
+    #[error("Database version {version} is newer than supported version {}", DATABASE_VERSION)]
+    UnsupportedVersion {
+        version: u32,
+    },
+}
+
+// The database as it appears on disk, before the columns are mapped onto
+// entries. Migrations operate on this so that they can add, remove or
+// rewrite columns without needing the old layout of Entry.
+struct RawDatabase {
+    version: u32,
+    // Incremented on every rewrite, so that the journal can tell which
+    // version of the file it applies to.
+    generation: u64,
+    columns: Vec<String>,
+    // Rows along with their line numbers
+    rows: Vec<(usize, Vec<String>)>,
+}
+
+struct Migration {
+    // Version this migration upgrades from; the result is version from + 1.
+    from: u32,
+    migrate: fn(&mut RawDatabase),
+}
+
+// Migrations in version order. Every format change should bump
+// DATABASE_VERSION and register a migration here.
+const MIGRATIONS: &[Migration] = &[
+    Migration { from: 1, migrate: migrate_add_version },
+    Migration { from: 2, migrate: migrate_escape_fields },
+    Migration { from: 3, migrate: migrate_add_starred },
+    Migration { from: 4, migrate: migrate_add_timestamps },
+];
+
+fn migrate_add_version(_database: &mut RawDatabase) {
+    // Version 1 databases are identical apart from lacking the version line,
+    // which is written out with the next modification.
+}
+
+fn migrate_escape_fields(database: &mut RawDatabase) {
+    // Version 2 fields were written verbatim (tabs and newlines having been
+    // stripped), so the only thing needing escaping is backslashes.
+    for (_, row) in database.rows.iter_mut() {
+        for field in row.iter_mut() {
+            *field = field.replace('\\', "\\\\");
+        }
+    }
+}
+
+fn migrate_add_starred(database: &mut RawDatabase) {
+    // Nothing was starred before version 4.
+    database.columns.push("starred".to_string());
+    for (_, row) in database.rows.iter_mut() {
+        row.push("unstarred".to_string());
+    }
+}
+
+fn migrate_add_timestamps(database: &mut RawDatabase) {
+    // Times weren't recorded before version 5, so are left empty.
+    database.columns.push("read_at".to_string());
+    database.columns.push("first_seen".to_string());
+    for (_, row) in database.rows.iter_mut() {
+        row.push(String::new());
+        row.push(String::new());
+    }
+}
+
+fn migrate_database(database: &mut RawDatabase) {
+    for migration in MIGRATIONS {
+        if migration.from == database.version {
+            (migration.migrate)(database);
+            database.version = migration.from + 1;
+        }
+    }
+}
+
+fn escape_field(field: &str) -> String {
+    // Escape a field so that it can't contain the tab and newline delimiters.
+
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+fn unescape_field(field: &str) -> String {
+    // Reverse escape_field. Unknown escapes are kept as they are.
+
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            },
+            None => unescaped.push('\\'),
+        }
+    }
+    return unescaped;
 }
 
-fn read_entries(filename: PathBuf) -> Result<Vec<Entry>, DatabaseReadError> {
+fn read_raw_database(filename: PathBuf, header_only: bool) -> Result<RawDatabase, DatabaseReadError> {
+    // Read the database without interpreting the columns.
+    // The database starts with optional "#key value" metadata lines, followed
+    // by a header naming the columns, followed by one entry per line.
+    // Databases without a version line predate versioning, so are version 1.
+
     let f = OpenOptions::new().read(true).open(&filename)
             .map_err(|e| DatabaseReadError::IoError{ source: e, path: filename.clone() })?;
     let reader = BufReader::new(f);
 
-    let mut entries: Vec<Entry> = Vec::new();
+    let mut version = 1;
+    let mut generation = 0;
+    let mut columns: Option<Vec<String>> = None;
+    let mut rows: Vec<(usize, Vec<String>)> = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| DatabaseReadError::IoError{ source: e, path: filename.clone() })?;
+        if columns.is_some() {
+            rows.push((index + 1, line.split('\t').map(|field| field.to_string()).collect()));
+        } else if let Some(metadata) = line.strip_prefix('#') {
+            // Unknown metadata is ignored, so that it can be extended later
+            let (key, value) = metadata.split_once(' ').unwrap_or((metadata, ""));
+            let invalid = || DatabaseReadError::InvalidMetadata{ key: key.to_string(), value: value.to_string() };
+            if key == "version" {
+                version = value.parse().map_err(|_| invalid())?;
+            } else if key == "generation" {
+                generation = value.parse().map_err(|_| invalid())?;
+            }
+        } else {
+            columns = Some(line.split('\t').map(|column| column.to_string()).collect());
+            if header_only {
+                break;
+            }
+        }
+    }
+
+    if version > DATABASE_VERSION {
+        return Err(DatabaseReadError::UnsupportedVersion{ version });
+    }
+    let columns = match columns {
+        Some(columns) => columns,
+        None => {
+            // Empty database; treat as the current version
+            version = DATABASE_VERSION;
+            DATABASE_COLUMNS.iter().map(|column| column.to_string()).collect()
+        },
+    };
+
+    return Ok(RawDatabase { version, generation, columns, rows });
+}
+
+fn get_sibling_path(database_path: &Path, suffix: &str) -> PathBuf {
+    // Path for a file stored alongside the database, eg the lockfile.
+    // We assume that database_path here has a filename, which should be true since it always comes
+    // from get_database_path.
+    let mut file_name = database_path.file_name().unwrap().to_os_string();
+    file_name.push(suffix);
+    return database_path.with_file_name(file_name);
+}
+
+struct JournalChange {
+    feed: String,
+    id: String,
+    read: bool,
+    // Missing from journals written before read times were recorded
+    time: Option<String>,
+}
+
+struct Journal {
+    generation: u64,
+    // Changes in the order they were made
+    changes: Vec<JournalChange>,
+}
+
+fn read_journal(journal_path: &Path, header_only: bool) -> Result<Option<Journal>, DatabaseReadError> {
+    // Read the journal of read state changes made since the database was
+    // last rewritten. The journal starts with a "#generation n" line
+    // recording which database generation it applies to, followed by one
+    // "feed\tid\tread|unread\ttime" line per change.
+    // Incomplete lines (eg from a crash part way through appending) are ignored.
+
+    let f = match OpenOptions::new().read(true).open(journal_path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(DatabaseReadError::IoError{ source: e, path: journal_path.to_path_buf() }),
+    };
+    let mut lines = BufReader::new(f).lines();
+
+    let generation = match lines.next() {
+        Some(line) => {
+            let line = line.map_err(|e| DatabaseReadError::IoError{ source: e, path: journal_path.to_path_buf() })?;
+            match line.strip_prefix("#generation ").and_then(|value| value.parse().ok()) {
+                Some(generation) => generation,
+                None => return Ok(None),
+            }
+        },
+        None => return Ok(None),
+    };
+
+    let mut changes = Vec::new();
+    if !header_only {
+        for line in lines {
+            let line = line.map_err(|e| DatabaseReadError::IoError{ source: e, path: journal_path.to_path_buf() })?;
+            changes.extend(parse_journal_line(&line));
+        }
+    }
+
+    return Ok(Some(Journal { generation, changes }));
+}
+
+fn parse_journal_line(line: &str) -> Option<JournalChange> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let (feed, id, state, time) = match fields[..] {
+        [feed, id, state] => (feed, id, state, None),
+        [feed, id, state, time] => (feed, id, state, Some(unescape_field(time))),
+        _ => return None,
+    };
+    if state != "read" && state != "unread" {
+        return None;
+    }
+    return Some(JournalChange {
+        feed: unescape_field(feed),
+        id: unescape_field(id),
+        read: state == "read",
+        time,
+    });
+}
+
+fn format_journal_line(change: &JournalChange) -> String {
+    return [
+        escape_field(&change.feed),
+        escape_field(&change.id),
+        if change.read { "read".to_string() } else { "unread".to_string() },
+        escape_field(change.time.as_deref().unwrap_or("")),
+    ].join("\t") + "\n";
+}
+
+fn apply_journal<'a>(entries: impl Iterator<Item = &'a mut Entry>, changes: &[JournalChange]) {
+    // Replay read state changes, in order, onto the entries they name.
+    let mut positions: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+    for (position, change) in changes.iter().enumerate() {
+        positions.entry((change.feed.as_str(), change.id.as_str())).or_default().push(position);
+    }
+    for entry in entries {
+        let matching = positions.get(&(entry.feed.as_str(), entry.id.as_str())).cloned().unwrap_or_default();
+        for position in matching {
+            entry.set_read_at(changes[position].read, changes[position].time.clone());
+        }
+    }
+}
+
+fn read_unbacked_changes(database_path: &Path) -> Result<Vec<JournalChange>, DatabaseReadError> {
+    // Read state changes made since the last backup was taken, which were
+    // folded into the database (or, for SQLite, made directly) without one.
+    // Undoing re-applies them, so that only the undone modifications are
+    // undone.
+    let path = get_sibling_path(database_path, ".reads");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(DatabaseReadError::IoError{ source: e, path }),
+    };
+    return Ok(contents.lines().filter_map(parse_journal_line).collect());
+}
+
+fn append_unbacked_changes(database_path: &Path, changes: &[JournalChange]) -> io::Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let mut f = OpenOptions::new().create(true).append(true).open(get_sibling_path(database_path, ".reads"))?;
+    let lines: String = changes.iter().map(format_journal_line).collect();
+    f.write_all(lines.as_bytes())?;
+    return f.sync_data();
+}
+
+// Ids of read entries which have dropped out of their feed, and when they
+// were last seen, so that they aren't added again if they reappear.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SeenIds {
+    ids: HashMap<(String, String), DateTime<Utc>>,
+}
+
+impl SeenIds {
+    pub fn contains(&self, feed_name: &str, entry_id: &str) -> bool {
+        return self.ids.contains_key(&(feed_name.to_string(), entry_id.to_string()));
+    }
+
+    pub fn insert(&mut self, feed_name: &str, entry_id: &str) {
+        // Records the id as seen now, restarting its expiry.
+        self.ids.insert((feed_name.to_string(), entry_id.to_string()), Utc::now());
+    }
+
+    pub fn remove_feed(&mut self, feed_name: &str) {
+        self.ids.retain(|(feed, _), _| feed != feed_name);
+    }
+
+    pub fn rename_feed(&mut self, feed_name: &str, new_feed_name: &str) {
+        let ids: Vec<((String, String), DateTime<Utc>)> = self.ids.iter()
+            .filter(|((feed, _), _)| feed == feed_name)
+            .map(|(key, last_seen)| (key.clone(), *last_seen))
+            .collect();
+        for ((_, entry_id), last_seen) in ids {
+            self.ids.remove(&(feed_name.to_string(), entry_id.clone()));
+            self.ids.insert((new_feed_name.to_string(), entry_id), last_seen);
+        }
+    }
+
+    fn expire(&mut self, days: u32) {
+        let cutoff = Utc::now() - chrono::Duration::days(days.into());
+        self.ids.retain(|_, last_seen| *last_seen >= cutoff);
+    }
+}
+
+fn read_seen_ids(seen_path: &Path) -> Result<SeenIds, DatabaseReadError> {
+    // One "feed\tid\tlast seen" line per id. Like the journal, the record
+    // is only advisory, so malformed lines are ignored.
+
+    let f = match OpenOptions::new().read(true).open(seen_path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(SeenIds::default()),
+        Err(e) => return Err(DatabaseReadError::IoError{ source: e, path: seen_path.to_path_buf() }),
+    };
+
+    let mut seen = SeenIds::default();
+    for line in BufReader::new(f).lines() {
+        let line = line.map_err(|e| DatabaseReadError::IoError{ source: e, path: seen_path.to_path_buf() })?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        if let [feed, id, last_seen] = fields[..] {
+            if let Ok(last_seen) = DateTime::parse_from_rfc3339(last_seen) {
+                seen.ids.insert((unescape_field(feed), unescape_field(id)), last_seen.with_timezone(&Utc));
+            }
+        }
+    }
+    return Ok(seen);
+}
 
-    for line in reader.lines().skip(1) {
-        match line {
-            Ok(line) => {
-                let mut fields = line.split("\t");
-                let entry = Entry {
-                    feed: fields.next().ok_or(DatabaseReadError::MissingField{ field: "feed".to_string() })?.to_string(),
-                    id: fields.next().ok_or(DatabaseReadError::MissingField{ field: "id".to_string() })?.to_string(),
-                    updated: fields.next().ok_or(DatabaseReadError::MissingField{ field: "updated".to_string() })?.to_string(),
-                    title: fields.next().ok_or(DatabaseReadError::MissingField{ field: "title".to_string() })?.to_string(),
-                    link: fields.next().ok_or(DatabaseReadError::MissingField{ field: "link".to_string() })?.to_string(),
-                    read: fields.next().ok_or(DatabaseReadError::MissingField{ field: "read".to_string() })? == "read",
-                };
-                entries.push(entry);
+fn write_seen_ids(seen_path: &Path, seen: &SeenIds) -> io::Result<()> {
+    let new_path = get_sibling_path(seen_path, ".new");
+    let mut f = fs::File::create(&new_path)?;
+    let result = (|| {
+        let mut writer = BufWriter::new(&mut f);
+        for ((feed, id), last_seen) in seen.ids.iter() {
+            let line = [escape_field(feed), escape_field(id), last_seen.to_rfc3339()].join("\t") + "\n";
+            writer.write_all(line.as_bytes())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        return f.sync_all();
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&new_path);
+        return Err(e);
+    }
+    return fs::rename(new_path, seen_path);
+}
+
+fn append_journal(database_path: &Path, feed_name: &str, entry_id: &str, read: bool) -> Result<u64, ModifyDatabaseError> {
+    // Record a read state change in the journal, returning the new size of
+    // the journal. The database lock must already be held.
+
+    let journal_path = get_sibling_path(database_path, ".journal");
+    let write_error = |e, operation: &str| ModifyDatabaseError::WriteError{
+        source: e, path: journal_path.clone(), operation: operation.to_string()
+    };
+
+    let database = read_raw_database(database_path.to_path_buf(), true)
+        .map_err(|e| ModifyDatabaseError::ReadError{ source: e })?;
+    let journal = read_journal(&journal_path, true)
+        .map_err(|e| ModifyDatabaseError::ReadError{ source: e })?;
+
+    if journal.map(|journal| journal.generation) != Some(database.generation) {
+        // Missing, or left over from before the last rewrite; start afresh
+        let header = format!("#generation {}\n", database.generation);
+        fs::write(&journal_path, header).map_err(|e| write_error(e, "write"))?;
+    }
+
+    let mut f = OpenOptions::new().read(true).append(true).open(&journal_path)
+        .map_err(|e| write_error(e, "open"))?;
+
+    // Terminate any incomplete line left by an earlier crash, so that it
+    // doesn't swallow this change
+    let mut last = [0u8; 1];
+    f.seek(SeekFrom::End(-1)).and_then(|_| f.read_exact(&mut last))
+        .map_err(|e| write_error(e, "read"))?;
+    let mut line = if last[0] == b'\n' { String::new() } else { "\n".to_string() };
+
+    line += &format_journal_line(&JournalChange {
+        feed: feed_name.to_string(),
+        id: entry_id.to_string(),
+        read,
+        time: Some(Utc::now().to_rfc3339()),
+    });
+    f.write_all(line.as_bytes()).map_err(|e| write_error(e, "write"))?;
+    f.sync_data().map_err(|e| write_error(e, "sync"))?;
+
+    return f.metadata().map(|metadata| metadata.len()).map_err(|e| write_error(e, "stat"));
+}
+
+#[derive(Debug)]
+pub struct DatabaseProblem {
+    pub line: usize,
+    pub error: DatabaseReadError,
+}
+
+pub struct DatabaseRows {
+    // Entries along with the line they were read from
+    pub rows: Vec<(usize, Entry)>,
+    pub problems: Vec<DatabaseProblem>,
+    generation: u64,
+}
+
+fn read_rows(filename: PathBuf, lenient: bool) -> Result<DatabaseRows, DatabaseReadError> {
+    // Read the entries and fold in the journal.
+    // The journal is read first: if the database is rewritten in the meantime
+    // the generations won't match and the journal (already folded into the
+    // new database) is ignored.
+    // In lenient mode, rows with missing fields are skipped and unknown read
+    // or starred states are treated as unread or unstarred, with the problems
+    // being returned instead of causing an error.
+
+    let journal = read_journal(&get_sibling_path(&filename, ".journal"), false)?;
+    let mut database = read_raw_database(filename, false)?;
+    migrate_database(&mut database);
+
+    // Map column names to positions; unknown columns are ignored
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for column in DATABASE_COLUMNS {
+        let position = database.columns.iter().position(|c| c == column)
+            .ok_or(DatabaseReadError::MissingColumn{ column: column.to_string() })?;
+        positions.insert(column, position);
+    }
+    let field = |line: usize, row: &Vec<String>, name: &str| -> Result<String, DatabaseReadError> {
+        return row.get(positions[name]).map(|field| unescape_field(field))
+            .ok_or(DatabaseReadError::MissingField{ field: name.to_string(), line });
+    };
+    // Empty for times which aren't known
+    let optional_field = |line: usize, row: &Vec<String>, name: &str| -> Result<Option<String>, DatabaseReadError> {
+        return field(line, row, name).map(|value| if value.is_empty() { None } else { Some(value) });
+    };
+    let parse_row = |line: usize, row: &Vec<String>| -> Result<Entry, DatabaseReadError> {
+        return Ok(Entry {
+            feed: field(line, row, "feed")?,
+            id: field(line, row, "id")?,
+            updated: field(line, row, "updated")?,
+            title: field(line, row, "title")?,
+            link: field(line, row, "link")?,
+            read: false,
+            starred: false,
+            read_at: optional_field(line, row, "read_at")?,
+            first_seen: optional_field(line, row, "first_seen")?,
+        });
+    };
+
+    let mut rows: Vec<(usize, Entry)> = Vec::new();
+    let mut problems: Vec<DatabaseProblem> = Vec::new();
+    let mut report = |e: DatabaseReadError, line: usize| -> Result<(), DatabaseReadError> {
+        if !lenient {
+            return Err(e);
+        }
+        problems.push(DatabaseProblem { line, error: e });
+        return Ok(());
+    };
+    for (line, row) in &database.rows {
+        let mut entry = match parse_row(*line, row) {
+            Ok(entry) => entry,
+            Err(e) => {
+                report(e, *line)?;
+                continue;
             },
+        };
+        let state = match field(*line, row, "read") {
+            Ok(state) => state,
             Err(e) => {
-                return Err(DatabaseReadError::IoError{ source: e, path: filename.clone() });
-            }
+                report(e, *line)?;
+                continue;
+            },
+        };
+        match state.as_str() {
+            "read" => entry.read = true,
+            "unread" => entry.read = false,
+            _ => report(DatabaseReadError::InvalidReadState{ value: state.clone(), line: *line }, *line)?,
+        }
+        let starred = match field(*line, row, "starred") {
+            Ok(starred) => starred,
+            Err(e) => {
+                report(e, *line)?;
+                continue;
+            },
+        };
+        match starred.as_str() {
+            "starred" => entry.starred = true,
+            "unstarred" => entry.starred = false,
+            _ => report(DatabaseReadError::InvalidStarredState{ value: starred.clone(), line: *line }, *line)?,
         }
+        rows.push((*line, entry));
     }
 
-    return Ok(entries);
+    if let Some(journal) = journal {
+        if journal.generation == database.generation {
+            apply_journal(rows.iter_mut().map(|(_, entry)| entry), &journal.changes);
+        }
+    }
+
+    return Ok(DatabaseRows { rows, problems, generation: database.generation });
+}
+
+fn read_entries(filename: PathBuf) -> Result<(Vec<Entry>, u64), DatabaseReadError> {
+    // Read the entries, also returning the generation.
+
+    let database = read_rows(filename, false)?;
+    let entries = database.rows.into_iter().map(|(_, entry)| entry).collect();
+    return Ok((entries, database.generation));
+}
+
+pub fn read_tsv_database_lenient(database_path: PathBuf) -> Result<DatabaseRows, DatabaseReadError> {
+    // Read a TSV database, skipping and reporting malformed rows rather than
+    // failing.
+
+    return read_rows(database_path, true);
+}
+
+pub fn write_tsv_database(database_path: PathBuf, entries: &Vec<Entry>) -> io::Result<()> {
+    // Write the entries to a new TSV database. Refuses to overwrite an
+    // existing file.
+
+    let mut f = OpenOptions::new().write(true).create_new(true).open(database_path)?;
+    write_entries(&mut f, entries, 1)?;
+    return f.sync_all();
 }
 
-fn write_entries(f: &mut fs::File, entries: &Vec<Entry>) -> io::Result<()> {
+fn write_entries(f: &mut fs::File, entries: &Vec<Entry>, generation: u64) -> io::Result<()> {
     let mut writer = BufWriter::new(f);
 
-    writer.write_all(ENTRY_DATABASE_HEADER.as_bytes())?;
+    writer.write_all(format!("#version {}\n", DATABASE_VERSION).as_bytes())?;
+    writer.write_all(format!("#generation {}\n", generation).as_bytes())?;
+    writer.write_all((DATABASE_COLUMNS.join("\t") + "\n").as_bytes())?;
 
     for e in entries {
-        // Can safely use tabs and newlines as delimiters as removed earlier
+        // Fields are escaped so tabs and newlines can be used as delimiters
         let line = [
-            e.feed.clone(),
-            e.id.clone(),
-            e.updated.clone(),
-            e.title.clone(),
-            e.link.clone(),
+            escape_field(&e.feed),
+            escape_field(&e.id),
+            escape_field(&e.updated),
+            escape_field(&e.title),
+            escape_field(&e.link),
             if e.read { "read".to_string() } else { "unread".to_string() },
+            if e.starred { "starred".to_string() } else { "unstarred".to_string() },
+            escape_field(e.read_at.as_deref().unwrap_or("")),
+            escape_field(e.first_seen.as_deref().unwrap_or("")),
         ].join("\t") + "\n";
         writer.write_all(line.as_bytes())?;
     }
@@ -366,6 +954,73 @@ fn write_entries(f: &mut fs::File, entries: &Vec<Entry>) -> io::Result<()> {
     return Ok(());
 }
 
+fn get_backup_path(database_path: &Path, number: usize) -> PathBuf {
+    // Backup 1 is the most recent.
+    return get_sibling_path(database_path, &format!(".backup.{}", number));
+}
+
+fn read_backup(database_path: &Path, number: usize) -> Result<Vec<Entry>, DatabaseReadError> {
+    // Backups are TSV databases, whatever the backend. Read state changes
+    // made since the last backup aren't part of any modification, so are
+    // kept.
+    let (mut entries, _) = read_entries(get_backup_path(database_path, number))?;
+    apply_journal(entries.iter_mut(), &read_unbacked_changes(database_path)?);
+    return Ok(entries);
+}
+
+fn read_tsv_backup(database_path: &Path, number: usize) -> Result<Vec<Entry>, DatabaseReadError> {
+    // As read_backup, also keeping the changes in the journal.
+    let mut entries = read_backup(database_path, number)?;
+    // The database may have been removed since (eg an emptied shard), but
+    // then so has its journal
+    if let Some(journal) = read_journal(&get_sibling_path(database_path, ".journal"), false)? {
+        let database = read_raw_database(database_path.to_path_buf(), true)?;
+        if journal.generation == database.generation {
+            apply_journal(entries.iter_mut(), &journal.changes);
+        }
+    }
+    return Ok(entries);
+}
+
+fn write_backup(database_path: &Path, entries: &Vec<Entry>, count: usize) -> io::Result<()> {
+    // Keep a copy of the entries as they were before a modification, moving
+    // the older backups along and dropping the oldest.
+
+    if count == 0 {
+        return Ok(());
+    }
+    for number in (1..count).rev() {
+        match fs::rename(get_backup_path(database_path, number), get_backup_path(database_path, number + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {},
+        }
+    }
+
+    // Written alongside first, so a partial backup is never left behind
+    let backup_path = get_backup_path(database_path, 1);
+    let new_path = get_sibling_path(&backup_path, ".new");
+    let mut f = fs::File::create(&new_path)?;
+    if let Err(e) = write_entries(&mut f, entries, 1) {
+        let _ = fs::remove_file(&new_path);
+        return Err(e);
+    }
+    return fs::rename(new_path, backup_path);
+}
+
+fn backup_entries(database_path: &Path, entries: &Vec<Entry>, modified_entries: &Vec<Entry>, count: usize) -> io::Result<()> {
+    // Only back up modifications which actually change something, so that
+    // upgrades and compaction don't push out useful backups.
+    // The backup includes any read state changes made since the last one.
+    if entries == modified_entries {
+        return Ok(());
+    }
+    write_backup(database_path, entries, count)?;
+    return match fs::remove_file(get_sibling_path(database_path, ".reads")) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    };
+}
+
 #[derive(Error, Debug)]
 pub enum ModifyDatabaseError {
     #[error("Unable to lock database: {source}: {path}")]
@@ -385,81 +1040,415 @@ pub enum ModifyDatabaseError {
     },
 }
 
-pub fn modify_database<F>(modifier: F, database_path: PathBuf) -> Result<(), ModifyDatabaseError>
-    where F: FnOnce(Vec<Entry>) -> Vec<Entry>
-{
-    // We assume that database_path here has a filename, which should be true since it always comes
-    // from get_database_path... but in that case maybe we should wrap it into here instead of
-    // getting it as an argument?
-    let mut lock_file_name = database_path.file_name().unwrap().to_os_string();
-    lock_file_name.push(std::ffi::OsStr::new(".lock"));
-    let mut lockfile_path = database_path.clone();
-    lockfile_path.set_file_name(lock_file_name);
+// The contents of a TSV database, read while holding its lock in order to
+// rewrite it.
+struct TsvContents {
+    entries: Vec<Entry>,
+    generation: u64,
+    seen: Option<SeenIds>,
+}
+
+fn lock_tsv_database(database_path: &Path, options: &StoreOptions) -> Result<lock::LockGuard, ModifyDatabaseError> {
+    let lockfile_path = get_sibling_path(database_path, ".lock");
+    return lock::acquire(&lockfile_path, &options.lock)
+           .map_err(|e| ModifyDatabaseError::LockCreateError{ source: e, path: lockfile_path.clone() });
+}
 
-    let mut lockfile = open_lockfile(lockfile_path.clone())
-                        .map_err(|e| ModifyDatabaseError::LockCreateError{ source: e, path: lockfile_path.clone() })?;
+fn read_tsv_contents(database_path: &Path, create: bool, track_seen: bool) -> Result<TsvContents, ModifyDatabaseError> {
+    // If create is set, a missing database is treated as empty rather than an
+    // error. If track_seen is set, the seen ids are read too.
 
-    // We need to delete the lockfile on failure!
+    let (entries, generation) = match read_entries(database_path.to_path_buf()) {
+        Err(DatabaseReadError::IoError{ source, .. }) if create && source.kind() == io::ErrorKind::NotFound => (Vec::new(), 0),
+        result => result.map_err(|e| ModifyDatabaseError::ReadError{ source: e })?,
+    };
+    let seen = match track_seen {
+        true => Some(read_seen_ids(&get_sibling_path(database_path, ".seen"))
+                     .map_err(|e| ModifyDatabaseError::ReadError{ source: e })?),
+        false => None,
+    };
+    return Ok(TsvContents { entries, generation, seen });
+}
+
+fn write_tsv_contents(database_path: &Path, options: &StoreOptions, contents: &TsvContents, modified_entries: &Vec<Entry>, seen: Option<SeenIds>) -> Result<(), ModifyDatabaseError> {
+    // Replace the contents read by read_tsv_contents, backing up the old
+    // entries. The database lock must still be held.
+    // The new database is written alongside and renamed into place.
+    let new_path = get_sibling_path(database_path, ".new");
+
+    // We need to delete the partially written database on failure!
     let cleanup_file = |e, path| -> ModifyDatabaseError {
         if let Err(err) = fs::remove_file(path) {
-            eprintln!("Unable to delete lockfile: {err}");
+            eprintln!("Unable to delete partial database: {err}");
         }
         e
     };
 
-    let entries = read_entries(database_path.clone())
-                    .map_err(|e| ModifyDatabaseError::ReadError{ source: e })
-                    .map_err(|e| cleanup_file(e, lockfile_path.clone()))?;
+    backup_entries(database_path, &contents.entries, modified_entries, options.backups)
+        .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: get_backup_path(database_path, 1), operation: "back up".to_string() })?;
+    if contents.entries == *modified_entries && options.backups > 0 {
+        // Compacting the journal; its changes still need keeping for undo
+        let journal_path = get_sibling_path(database_path, ".journal");
+        let journal = read_journal(&journal_path, false).map_err(|e| ModifyDatabaseError::ReadError{ source: e })?;
+        if let Some(journal) = journal.filter(|journal| journal.generation == contents.generation) {
+            append_unbacked_changes(database_path, &journal.changes)
+                .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: get_sibling_path(database_path, ".reads"), operation: "record read states for".to_string() })?;
+        }
+    }
+
+    // Written first, so that a crash can only leave extra seen ids behind
+    if let Some(mut seen) = seen {
+        let seen_path = get_sibling_path(database_path, ".seen");
+        seen.expire(options.seen_days);
+        write_seen_ids(&seen_path, &seen)
+            .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: seen_path.clone(), operation: "write seen ids for".to_string() })?;
+    }
+
+    let mut new_file = fs::File::create(&new_path)
+        .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: new_path.clone(), operation: "create".to_string() })?;
 
-    let modified_entries = modifier(entries);
+    write_entries(&mut new_file, modified_entries, contents.generation + 1)
+        .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: new_path.clone(), operation: "write".to_string() })
+        .map_err(|e| cleanup_file(e, new_path.clone()))?;
 
-    write_entries(&mut lockfile, &modified_entries)
-        .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: lockfile_path.clone(), operation: "write".to_string() })
-        .map_err(|e| cleanup_file(e, lockfile_path.clone()))?;
+    new_file.sync_all()
+        .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: new_path.clone(), operation: "sync".to_string() })
+        .map_err(|e| cleanup_file(e, new_path.clone()))?;
 
-    lockfile.sync_all()
-        .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: lockfile_path.clone(), operation: "sync".to_string() })
-        .map_err(|e| cleanup_file(e, lockfile_path.clone()))?;
+    fs::rename(new_path.clone(), database_path)
+        .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: new_path.clone(), operation: "replace".to_string() })
+        .map_err(|e| cleanup_file(e, new_path.clone()))?;
 
-    fs::rename(lockfile_path.clone(), database_path)
-        .map_err(|e| ModifyDatabaseError::WriteError{ source: e, path: lockfile_path.clone(), operation: "replace".to_string() })
-        .map_err(|e| cleanup_file(e, lockfile_path.clone()))?;
+    // The journal has been folded in, and no longer matches the generation
+    // anyway, so failing to delete it is harmless.
+    let _ = fs::remove_file(get_sibling_path(database_path, ".journal"));
 
     return Ok(());
 }
 
-fn merge_feed(feed_name: String, feed_entries: Vec<Entry>, database_entries: Vec<Entry>) -> Vec<Entry> {
-    // Merging a feed:
-    // - entries in the feed but not in the database are added
-    // - read entries in the database but not in the feed are removed
+fn modify_tsv_database<F>(modifier: F, database_path: PathBuf, options: &StoreOptions, create: bool, track_seen: bool) -> Result<(), ModifyDatabaseError>
+    where F: FnOnce(Vec<Entry>, &mut SeenIds) -> Vec<Entry>
+{
+    // Rewrite the database with the result of the modifier, while holding
+    // the lock; the lock is released when the guard is dropped.
+    // If track_seen is unset, the modifier is given an empty set of seen ids
+    // and any changes to it are discarded.
+    let _lock = lock_tsv_database(&database_path, options)?;
 
-    // Treat all entries from the feed as new initially
-    let mut new_feed_entries = HashMap::new();
-    for entry in feed_entries.into_iter() {
-        new_feed_entries.insert(entry.id.clone(), entry);
+    let contents = read_tsv_contents(&database_path, create, track_seen)?;
+    let mut seen = contents.seen.clone().unwrap_or_default();
+    let modified_entries = modifier(contents.entries.clone(), &mut seen);
+
+    return write_tsv_contents(&database_path, options, &contents, &modified_entries, if track_seen { Some(seen) } else { None });
+}
+
+fn set_tsv_read_state(database_path: PathBuf, options: &StoreOptions, feed_name: &str, entry_id: &str, read: bool) -> Result<(), ModifyDatabaseError> {
+    // Change the read state of a single entry by appending to the journal,
+    // rather than rewriting the whole database. The journal is compacted
+    // once it gets too large.
+
+    let lock = lock_tsv_database(&database_path, options)?;
+
+    let journal_size = append_journal(&database_path, feed_name, entry_id, read)?;
+    drop(lock);
+
+    if journal_size > JOURNAL_COMPACT_SIZE {
+        modify_tsv_database(|entries, _| entries, database_path, options, false, false)?;
+    }
+    return Ok(());
+}
+
+fn upgrade_tsv_database(database_path: PathBuf, options: &StoreOptions) -> Result<bool, ModifyDatabaseError> {
+    // Upgrade an older database in place, returning whether anything needed
+    // upgrading. Only the header is read unless an upgrade is required; the
+    // upgrade itself is just an unmodified rewrite under the usual lock.
+
+    let database = read_raw_database(database_path.clone(), true)
+        .map_err(|e| ModifyDatabaseError::ReadError{ source: e })?;
+    if database.version >= DATABASE_VERSION {
+        return Ok(false);
+    }
+
+    modify_tsv_database(|entries, _| entries, database_path, options, false, false)?;
+    return Ok(true);
+}
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error(transparent)]
+    DatabasePathError(#[from] DatabasePathError),
+    #[error("Unknown database backend: {backend}")]
+    UnknownBackend {
+        backend: String,
+    },
+    #[error("Database backend {backend} is not supported by this build")]
+    UnsupportedBackend {
+        backend: String,
+    },
+    #[error("Database read error: {source}")]
+    ReadError {
+        source: DatabaseReadError,
+    },
+    #[error(transparent)]
+    ModifyDatabaseError(#[from] ModifyDatabaseError),
+    #[error("Unable to back up database: {source}: {path}")]
+    BackupError {
+        source: io::Error,
+        path: PathBuf,
+    },
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {source}: {path}")]
+    SqliteError {
+        source: rusqlite::Error,
+        path: PathBuf,
+    },
+}
+
+// Modifier given to EntryStore::modify_seen.
+pub type SeenModifier<'a> = Box<dyn FnOnce(Vec<Entry>, &mut SeenIds) -> Vec<Entry> + 'a>;
+
+pub trait EntryStore {
+    // All entries in the database.
+    fn entries(&self) -> Result<Vec<Entry>, StoreError>;
+
+    // The entries of a single feed.
+    fn feed_entries(&self, feed_name: &str) -> Result<Vec<Entry>, StoreError> {
+        let mut entries = self.entries()?;
+        entries.retain(|entry| entry.feed == feed_name);
+        return Ok(entries);
+    }
+
+    // Replace the entries with the result of the modifier, which is given the
+    // current entries. The modification must be atomic with respect to other
+    // readers and writers.
+    fn modify(&self, modifier: Box<dyn FnOnce(Vec<Entry>) -> Vec<Entry> + '_>) -> Result<(), StoreError>;
+
+    // As modify, but the modifier can also consult and update the ids seen
+    // in feeds before, atomically with the entries. Seen ids older than the
+    // configured expiry are dropped.
+    fn modify_seen(&self, modifier: SeenModifier<'_>) -> Result<(), StoreError>;
+
+    // The ids seen in feeds before.
+    fn seen_ids(&self) -> Result<SeenIds, StoreError>;
+
+    // As modify_seen, but only the given feed's entries are passed to and
+    // returned from the modifier. Backends should override this where feeds
+    // can be modified independently.
+    fn modify_feed(&self, feed_name: &str, modifier: SeenModifier<'_>) -> Result<(), StoreError> {
+        return self.modify_seen(Box::new(move |entries: Vec<Entry>, seen: &mut SeenIds| -> Vec<Entry> {
+            let (feed_entries, mut modified_entries): (Vec<Entry>, Vec<Entry>) =
+                entries.clone().into_iter().partition(|entry| entry.feed == feed_name);
+            let modified_feed_entries = modifier(feed_entries.clone(), seen);
+            if modified_feed_entries == feed_entries {
+                // Keep the original order, so nothing looks modified
+                return entries;
+            }
+            modified_entries.extend(modified_feed_entries);
+            return modified_entries;
+        }));
+    }
+
+    // The entries as they were before the given number of modifications, as
+    // saved in the backups.
+    fn read_backup(&self, number: usize) -> Result<Vec<Entry>, StoreError>;
+
+    // Create an empty database if one does not already exist.
+    fn initialize(&self) -> Result<(), StoreError>;
+
+    // Lockfiles used by this backend, for inspecting and clearing stale locks.
+    fn lockfiles(&self) -> Vec<PathBuf> {
+        return Vec::new();
     }
 
-    let mut modified_database_entries: Vec<Entry> = Vec::new();
-    for entry in database_entries {
-        if entry.feed != feed_name {
-            // For a different feed; retain
-            modified_database_entries.push(entry);
-        } else if new_feed_entries.contains_key(&entry.id) {
-            // Not actually a new entry
-            new_feed_entries.remove(&entry.id);
-            modified_database_entries.push(entry);
-        } else if !entry.read {
-            // Not in the feed, but not yet read; keep
-            modified_database_entries.push(entry);
+    // Change the read state of a single entry. Backends should override this
+    // where it can be done more cheaply than a full modification.
+    fn set_read(&self, feed_name: &str, entry_id: &str, read: bool) -> Result<(), StoreError> {
+        return self.modify(Box::new(|mut entries: Vec<Entry>| -> Vec<Entry> {
+            for entry in entries.iter_mut().filter(|entry| entry.feed == feed_name && entry.id == entry_id) {
+                entry.set_read(read);
+            }
+            return entries;
+        }));
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StoreOptions {
+    pub lock: LockOptions,
+    // Number of backups to keep of the database before each modification
+    pub backups: usize,
+    // Days to remember seen ids for after they were last in a feed
+    pub seen_days: u32,
+}
+
+impl Default for StoreOptions {
+    fn default() -> StoreOptions {
+        return StoreOptions {
+            lock: LockOptions::default(),
+            backups: 5,
+            seen_days: 90,
+        };
+    }
+}
+
+pub struct TsvStore {
+    path: PathBuf,
+    options: StoreOptions,
+}
+
+impl TsvStore {
+    pub fn new(path: PathBuf) -> TsvStore {
+        return TsvStore::with_options(path, StoreOptions::default());
+    }
+
+    pub fn with_options(path: PathBuf, options: StoreOptions) -> TsvStore {
+        return TsvStore { path, options };
+    }
+}
+
+impl EntryStore for TsvStore {
+    fn entries(&self) -> Result<Vec<Entry>, StoreError> {
+        upgrade_tsv_database(self.path.clone(), &self.options)?;
+        return read_entries(self.path.clone())
+            .map(|(entries, _)| entries)
+            .map_err(|e| StoreError::ReadError{ source: e });
+    }
+
+    fn modify(&self, modifier: Box<dyn FnOnce(Vec<Entry>) -> Vec<Entry> + '_>) -> Result<(), StoreError> {
+        return Ok(modify_tsv_database(|entries, _| modifier(entries), self.path.clone(), &self.options, false, false)?);
+    }
+
+    fn modify_seen(&self, modifier: SeenModifier<'_>) -> Result<(), StoreError> {
+        return Ok(modify_tsv_database(modifier, self.path.clone(), &self.options, false, true)?);
+    }
+
+    fn seen_ids(&self) -> Result<SeenIds, StoreError> {
+        return read_seen_ids(&get_sibling_path(&self.path, ".seen"))
+            .map_err(|e| StoreError::ReadError{ source: e });
+    }
+
+    fn read_backup(&self, number: usize) -> Result<Vec<Entry>, StoreError> {
+        return read_tsv_backup(&self.path, number).map_err(|e| StoreError::ReadError{ source: e });
+    }
+
+    fn initialize(&self) -> Result<(), StoreError> {
+        return Ok(modify_tsv_database(|entries, _| entries, self.path.clone(), &self.options, true, false)?);
+    }
+
+    fn lockfiles(&self) -> Vec<PathBuf> {
+        return vec![get_sibling_path(&self.path, ".lock")];
+    }
+
+    fn set_read(&self, feed_name: &str, entry_id: &str, read: bool) -> Result<(), StoreError> {
+        return Ok(set_tsv_read_state(self.path.clone(), &self.options, feed_name, entry_id, read)?);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backend {
+    Tsv,
+    Sqlite,
+    // A TSV database per feed
+    Sharded,
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Result<Backend, StoreError> {
+        match name {
+            "tsv" => Ok(Backend::Tsv),
+            "sqlite" => Ok(Backend::Sqlite),
+            "sharded" => Ok(Backend::Sharded),
+            _ => Err(StoreError::UnknownBackend{ backend: name.to_string() }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Tsv => "tsv",
+            Backend::Sqlite => "sqlite",
+            Backend::Sharded => "sharded",
         }
     }
 
+    fn file_name(&self, profile: Option<&str>) -> String {
+        // Each profile has its own database
+        let stem = match profile {
+            Some(profile) => format!("feedutils-{}", profile),
+            None => "feedutils".to_string(),
+        };
+        return match self {
+            Backend::Tsv => stem + ".tsv",
+            Backend::Sqlite => stem + ".sqlite",
+            Backend::Sharded => stem + ".d",
+        };
+    }
+}
+
+pub fn open_backend_store(config: &Config, backend: Backend, database_path: PathBuf) -> Result<Box<dyn EntryStore>, StoreError> {
+    // Open a database with the configured locking and history.
+    match backend {
+        Backend::Tsv => Ok(Box::new(TsvStore::with_options(database_path, config.store))),
+        Backend::Sharded => Ok(Box::new(ShardedStore::with_options(database_path, config.store))),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Ok(Box::new(SqliteStore::with_options(database_path, config.store))),
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => Err(StoreError::UnsupportedBackend{ backend: backend.name().to_string() }),
+    }
+}
+
+pub fn open_store(config: &Config) -> Result<Box<dyn EntryStore>, StoreError> {
+    // Open the configured database.
+
+    let database_path = get_database_path(config, config.backend)?;
+    return open_backend_store(config, config.backend, database_path);
+}
+
+pub fn modify_database<F>(config: &Config, modifier: F) -> Result<(), StoreError>
+    where F: FnOnce(Vec<Entry>) -> Vec<Entry>
+{
+    return open_store(config)?.modify(Box::new(modifier));
+}
+
+fn merge_feed(feed_name: String, feed_entries: Vec<Entry>, database_entries: Vec<Entry>, seen: &mut SeenIds) -> Vec<Entry> {
+    // Merging a feed:
+    // - entries in the feed but not in the database are added, unless they
+    //   have been seen (and read) before
+    // - read entries in the database but not in the feed are removed, and
+    //   remembered as seen, unless they are starred
+
+    let mut index = EntryIndex::new(database_entries);
+    let mut new_feed_entries: Vec<Entry> = Vec::new();
+    let mut feed_ids: HashSet<String> = HashSet::new();
+    for entry in feed_entries.into_iter() {
+        if feed_ids.insert(entry.id.clone()) && !index.contains(&feed_name, &entry.id) {
+            new_feed_entries.push(entry);
+        }
+    }
+
+    // Entries no longer in the feed, which are read and not kept for later
+    let removed_ids: Vec<String> = index.feed_entries(&feed_name)
+        .filter(|entry| !feed_ids.contains(&entry.id) && entry.read && !entry.starred)
+        .map(|entry| entry.id.clone())
+        .collect();
+    for entry_id in removed_ids {
+        index.remove(&feed_name, &entry_id);
+        seen.insert(&feed_name, &entry_id);
+    }
+
     // Add the actually new entries
-    for entry in new_feed_entries.into_values() {
-        modified_database_entries.push(entry);
+    let now = Utc::now().to_rfc3339();
+    for mut entry in new_feed_entries {
+        if seen.contains(&feed_name, &entry.id) {
+            // Reappeared after being read; still in the feed, so keep
+            // remembering it
+            seen.insert(&feed_name, &entry.id);
+            continue;
+        }
+        entry.first_seen = Some(now.clone());
+        index.insert(entry);
     }
 
-    return modified_database_entries;
+    return index.into_entries();
 }
 
 #[derive(Error, Debug)]
@@ -468,38 +1457,30 @@ pub enum DatabasePathError {
     NoEnvVar,
 }
 
-pub fn get_database_path() -> Result<PathBuf, DatabasePathError> {
+pub fn get_database_path(config: &Config, backend: Backend) -> Result<PathBuf, DatabasePathError> {
     // Database path; check possible settings env vars in sequence.
     // Does not check if the directory or file actually exists.
-    
-    if let Some(path) = env::var_os("FEEDUTILS_DB") {
-        return Ok(PathBuf::from(path));
+    // A configured path is for the configured backend; others (eg when
+    // migrating) use their default path, which depends on the profile.
+
+    if let (true, Some(path)) = (backend == config.backend, &config.database) {
+        return Ok(path.clone());
     }
+    let file_name = backend.file_name(config.profile.as_deref());
     if let Some(path) = env::var_os("XDG_DATA_HOME") {
-        return Ok(PathBuf::from(path).join("feedutils.tsv"));
+        return Ok(PathBuf::from(path).join(file_name));
     }
     if let Some(path) = env::var_os("HOME") {
-        return Ok(PathBuf::from(path).join(".local/share/feedutils.tsv"));
+        return Ok(PathBuf::from(path).join(".local/share").join(file_name));
     }
 
     return Err(DatabasePathError::NoEnvVar);
 }
 
-fn get_feed_config_dir() -> Option<PathBuf> {
-    // Feed configuration directory path; check possible settings env vars in sequence.
+fn get_feed_config_dir(config: &Config) -> Option<PathBuf> {
+    // Feed configuration directory path, as configured.
     // Does not check if the directory actually exists.
-
-    if let Some(path) = env::var_os("FEEDUTILS_CONFIGDIR") {
-        return Some(PathBuf::from(path));
-    }
-    if let Some(path) = env::var_os("XDG_CONFIG_HOME") {
-        return Some(PathBuf::from(path).join("feeds"));
-    }
-    if let Some(path) = env::var_os("HOME") {
-        return Some(PathBuf::from(path).join(".config/feeds"));
-    }
-
-    return None;
+    return config.feed_dir.clone();
 }
 
 #[derive(Error, Debug)]
@@ -510,21 +1491,56 @@ pub enum FeedDirError {
     FeedDoesNotExist,
 }
 
-pub fn get_feed_dir(feed_name: String) -> Result<PathBuf, FeedDirError> {
-    let config_dir = get_feed_config_dir().ok_or(FeedDirError::CannotReadConfigDir)?;
+pub fn get_feed_dir(config: &Config, feed_name: String) -> Result<PathBuf, FeedDirError> {
+    let config_dir = get_feed_config_dir(config).ok_or(FeedDirError::CannotReadConfigDir)?;
     fs::metadata(config_dir.clone()).map_err(|_| FeedDirError::CannotReadConfigDir)?;
     let feed_dir = config_dir.join(feed_name);
     fs::metadata(feed_dir.clone()).map_err(|_| FeedDirError::FeedDoesNotExist)?;
     return Ok(feed_dir);
 }
 
-pub fn get_all_feed_names() -> io::Result<Vec<String>> {
+// Directory in the feed configuration directory which deleted feeds are moved
+// to, so that undoing the deletion can restore them
+const DELETED_FEEDS_DIR: &str = ".deleted";
+
+pub fn delete_feed_dir(config: &Config, feed_name: &str) -> Result<(), RenameFeedError> {
+    // Move a feed's configuration aside, replacing any previously deleted
+    // feed of the same name.
+    let feed_dir = get_feed_dir(config, feed_name.to_string())?;
+    let deleted_dir = feed_dir.with_file_name(DELETED_FEEDS_DIR);
+    fs::create_dir_all(&deleted_dir).map_err(|e| RenameFeedError::RenameError{ source: e, path: deleted_dir.clone() })?;
+    let deleted_feed_dir = deleted_dir.join(feed_name);
+    match fs::remove_dir_all(&deleted_feed_dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(RenameFeedError::RenameError{ source: e, path: deleted_feed_dir }),
+        _ => {},
+    }
+    fs::rename(&feed_dir, &deleted_feed_dir).map_err(|e| RenameFeedError::RenameError{ source: e, path: feed_dir })?;
+    return Ok(());
+}
+
+pub fn get_deleted_feed_dir(config: &Config, feed_name: &str) -> Result<PathBuf, FeedDirError> {
+    // As get_feed_dir, for a feed moved aside by delete_feed_dir.
+    return get_feed_dir(config, Path::new(DELETED_FEEDS_DIR).join(feed_name).to_string_lossy().into_owned());
+}
+
+pub fn restore_feed_dir(config: &Config, feed_name: &str) -> Result<(), RenameFeedError> {
+    // Move a deleted feed's configuration back.
+    let deleted_feed_dir = get_deleted_feed_dir(config, feed_name)?;
+    let feed_dir = deleted_feed_dir.parent().unwrap().with_file_name(feed_name);
+    if feed_dir.exists() {
+        return Err(RenameFeedError::FeedExists{ name: feed_name.to_string() });
+    }
+    fs::rename(&deleted_feed_dir, &feed_dir).map_err(|e| RenameFeedError::RenameError{ source: e, path: deleted_feed_dir })?;
+    return Ok(());
+}
+
+pub fn get_all_feed_names(config: &Config) -> io::Result<Vec<String>> {
     let mut feeds = Vec::new();
-    let config_dir = get_feed_config_dir().ok_or(io::ErrorKind::Other)?;
+    let config_dir = get_feed_config_dir(config).ok_or(io::ErrorKind::Other)?;
     for entry in fs::read_dir(config_dir)? {
         let entry = entry?;
         let filetype = entry.file_type()?;
-        if filetype.is_dir() {
+        if filetype.is_dir() && entry.file_name() != DELETED_FEEDS_DIR {
             // We sanitize the feed name as it may be used later in the database
             let sanitized_name = sanitize(entry.file_name().to_string_lossy().into_owned());
             feeds.push(sanitized_name);
@@ -536,8 +1552,6 @@ pub fn get_all_feed_names() -> io::Result<Vec<String>> {
 
 #[derive(Error, Debug)]
 pub enum UpdateError {
-    #[error(transparent)]
-    DatabasePathError(#[from] DatabasePathError),
     #[error(transparent)]
     FeedDirError(#[from] FeedDirError),
     #[error("Failed to launch fetch executable: {source}: {path}")]
@@ -545,68 +1559,404 @@ pub enum UpdateError {
         source: io::Error,
         path: String
     },
+    #[error("Failed to create headers file: {source}")]
+    HeadersFileError {
+        source: io::Error,
+    },
     #[error("Failed to run fetch, got {status}")]
     FetchError {
         stderr: Vec<u8>,
         status: ExitStatus,
     },
+    #[error("Fetch timed out after {}s", .timeout.as_secs_f64())]
+    FetchTimeout {
+        stderr: Vec<u8>,
+        timeout: Duration,
+    },
+    #[error("Fetch output exceeded {limit} bytes")]
+    FetchTooLarge {
+        stderr: Vec<u8>,
+        limit: u64,
+    },
+    #[error("Fetch failed unexpectedly: {message}")]
+    FetchPanicked {
+        message: String,
+    },
+    #[error("Failed to fetch: {source}: {url}")]
+    HttpError {
+        source: Box<ureq::Transport>,
+        url: String,
+    },
+    #[error("Failed to fetch, got HTTP {status} {reason}: {url}")]
+    HttpStatus {
+        status: u16,
+        reason: String,
+        url: String,
+    },
+    #[error("Failed to read response: {source}: {url}")]
+    HttpReadError {
+        source: io::Error,
+        url: String,
+    },
     #[error("Failed to update database: {source}")]
     DatabaseError {
-        source: ModifyDatabaseError,
+        source: StoreError,
     },
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
 }
 
-pub fn update(feed_name: String) -> Result<(), UpdateError> {
-    let feed_dir_path = get_feed_dir(feed_name.clone())?;
+fn feed_command(feed_dir_path: &Path, feed_name: &str, executable: &str, default: Option<String>) -> Command {
+    // The feed's own executable, or else the configured default command,
+    // which is run with the feed name and URL (from its url file) in the FEED
+    // and URL environment variables.
+    let exec_path = feed_dir_path.join(executable);
+    let default = match default {
+        Some(default) if fs::symlink_metadata(&exec_path).is_err() => default,
+        _ => return Command::new(exec_path),
+    };
+    let url = fs::read_to_string(feed_dir_path.join("url")).unwrap_or_default();
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(default).env("FEED", feed_name).env("URL", url.trim());
+    return command;
+}
+
+pub enum FetchedFeed {
+    // The feed's entries, and the validators to save once they are merged
+    Modified(Vec<Entry>, Validators),
+    // The feed hasn't changed since it was last fetched
+    NotModified,
+}
 
-    let exec_path = feed_dir_path.clone().join("fetch");
+pub fn update(config: &Config, feed_name: String) -> Result<(), UpdateError> {
+    let result = fetch_feed(config, &feed_name).and_then(|fetched| merge_fetched_feed(config, &feed_name, fetched));
+    // Like error.log, the health record is only for the user's benefit
+    let _ = record_update(config, &feed_name, &result);
+    return result;
+}
+
+pub fn fetch_feed(config: &Config, feed_name: &str) -> Result<FetchedFeed, UpdateError> {
+    // Fetch the feed and parse it, unless it hasn't changed.
+    // This doesn't touch the database, so can be run for several feeds at
+    // once.
+
+    let feed_dir_path = get_feed_dir(config, feed_name.to_string())?;
     let error_path = feed_dir_path.join("error.log");
 
-    let output = Command::new(exec_path.clone()).output()
-                .map_err(|e| UpdateError::ExecError{ source: e, path: exec_path.display().to_string() })?;
     // On failure, save the error into a file so that a later interactive
     // program can tell the user about the program. On success, delete any
     // such error files. We don't really care if that fails though.
-    if !output.status.success() {
-        let _ = fs::write(error_path, output.stderr.clone());
+    match fetch_feed_contents(config, &feed_dir_path, feed_name) {
+        Ok(fetched) => {
+            // If an old error file exists, delete it
+            let _ = fs::remove_file(error_path);
+            return Ok(match fetched {
+                Some((contents, validators)) => FetchedFeed::Modified(parse_feed(contents.as_slice(), feed_name), validators),
+                None => FetchedFeed::NotModified,
+            });
+        },
+        Err(error) => {
+            let _ = fs::write(error_path, error_log(&error));
+            return Err(error);
+        },
+    }
+}
+
+fn fetch_feed_contents(config: &Config, feed_dir_path: &Path, feed_name: &str) -> Result<Option<(Vec<u8>, Validators)>, UpdateError> {
+    // Run the feed's fetch executable (or the configured default fetch
+    // command), or else download its url file with the built-in client.
+    // Either is given the saved validators, and the result is None if the
+    // feed hasn't changed since.
+
+    let exec_path = feed_dir_path.join("fetch");
+    let timeout = get_feed_timeout(feed_dir_path, config.update.timeout)?;
+    let validators = Validators::read(&feed_dir_path.join("validators"));
+
+    if fs::symlink_metadata(&exec_path).is_err() && config.fetch.is_none() {
+        let url = fs::read_to_string(feed_dir_path.join("url")).unwrap_or_default();
+        if !url.trim().is_empty() {
+            return http::fetch_url(url.trim(), &validators, timeout, config.update.max_size);
+        }
+    }
+
+    // Fetch executables are given the validators in ETAG and LAST_MODIFIED,
+    // and can write the response headers to HEADERS to report new ones or a
+    // 304 Not Modified response. HEADERS is a new temporary file each time,
+    // removed once it has been read.
+    let headers_file = tempfile::Builder::new().prefix("feedutils-headers.").tempfile()
+        .map_err(|e| UpdateError::HeadersFileError{ source: e })?;
+    let mut command = feed_command(feed_dir_path, feed_name, "fetch", config.fetch.clone());
+    command.env("ETAG", validators.etag.as_deref().unwrap_or_default())
+        .env("LAST_MODIFIED", validators.last_modified.as_deref().unwrap_or_default())
+        .env("HEADERS", headers_file.path());
+    let output = fetch::run_fetch(command, timeout, config.update.max_size)
+                 .map_err(|e| UpdateError::ExecError{ source: e, path: exec_path.display().to_string() })?;
+    let headers = fs::read(headers_file.path()).unwrap_or_default();
+    drop(headers_file);
+    if output.timed_out {
+        return Err(UpdateError::FetchTimeout{ stderr: output.stderr, timeout: timeout.unwrap_or_default() });
+    } else if output.too_large {
+        return Err(UpdateError::FetchTooLarge{ stderr: output.stderr, limit: config.update.max_size });
+    } else if !output.status.success() {
         return Err(UpdateError::FetchError{ stderr: output.stderr, status: output.status });
-    } else {
-        // If an old error file exists, delete it
-        let _ = fs::remove_file(error_path);
     }
+    let (status, new_validators) = validators::parse_headers(&String::from_utf8_lossy(&headers));
+    if status == Some(304) && !validators.is_empty() {
+        return Ok(None);
+    }
+    return Ok(Some((output.stdout, new_validators)));
+}
 
-    let feed_entries = parse_feed(output.stdout.as_slice(), &feed_name);
-    let merge = |entries: Vec<Entry>| -> Vec<Entry> {
-        return merge_feed(feed_name, feed_entries, entries);
+fn error_log(error: &UpdateError) -> Vec<u8> {
+    // Contents of error.log: what the fetch executable printed, followed by
+    // why it failed.
+    let mut log = match error {
+        UpdateError::FetchError{ stderr, .. }
+            | UpdateError::FetchTimeout{ stderr, .. }
+            | UpdateError::FetchTooLarge{ stderr, .. } => stderr.clone(),
+        _ => Vec::new(),
     };
-    let database_path = get_database_path().map_err(|e| UpdateError::DatabasePathError(e))?;
-    return modify_database(merge, database_path)
-        .map_err(|e| UpdateError::DatabaseError{ source: e });
+    if !log.is_empty() && !log.ends_with(b"\n") {
+        log.push(b'\n');
+    }
+    log.extend_from_slice(error.to_string().as_bytes());
+    log.push(b'\n');
+    return log;
+}
+
+fn get_feed_timeout(feed_dir_path: &Path, default: Option<Duration>) -> Result<Option<Duration>, ConfigError> {
+    // The timeout from the feed's timeout file, in seconds, or else the
+    // configured one.
+    let timeout_path = feed_dir_path.join("timeout");
+    return match fs::read_to_string(&timeout_path) {
+        Ok(timeout) => config::parse_timeout(&timeout).ok_or_else(|| ConfigError::InvalidSetting{
+            name: timeout_path.display().to_string(),
+            value: timeout.trim().to_string(),
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(default),
+        Err(e) => Err(ConfigError::ReadError{ source: e, path: timeout_path }),
+    };
+}
+
+pub fn merge_feed_entries(config: &Config, feed_name: &str, feed_entries: Vec<Entry>) -> Result<(), UpdateError> {
+    // Merge freshly fetched entries into the database.
+    let merge = |entries: Vec<Entry>, seen: &mut SeenIds| -> Vec<Entry> {
+        return merge_feed(feed_name.to_string(), feed_entries, entries, seen);
+    };
+    return open_store(config)
+           .and_then(|store| store.modify_feed(feed_name, Box::new(merge)))
+           .map_err(|e| UpdateError::DatabaseError{ source: e });
+}
+
+pub fn merge_fetched_feed(config: &Config, feed_name: &str, fetched: FetchedFeed) -> Result<(), UpdateError> {
+    // Merge a fetched feed, then save its validators; they're only saved once
+    // the entries are safely in the database, or a failed merge would leave
+    // the next fetch thinking they were already there.
+    // An unchanged feed is left alone.
+    let (feed_entries, validators) = match fetched {
+        FetchedFeed::Modified(feed_entries, validators) => (feed_entries, validators),
+        FetchedFeed::NotModified => return Ok(()),
+    };
+    merge_feed_entries(config, feed_name, feed_entries)?;
+    // Failing to save them just means a full download next time
+    if let Ok(feed_dir_path) = get_feed_dir(config, feed_name.to_string()) {
+        let _ = validators.write(&feed_dir_path.join("validators"));
+    }
+    return Ok(());
+}
+
+pub fn get_feed_host(config: &Config, feed_name: &str) -> Option<String> {
+    // Host the feed is fetched from, if it can be worked out.
+    let url = read_subscription(config, feed_name).ok()?.url?;
+    return Url::parse(&url).ok()?.host_str().map(|host| host.to_string());
 }
 
 #[derive(Error, Debug)]
 pub enum MarkEntryAsReadError {
     #[error(transparent)]
-    DatabasePathError(#[from] DatabasePathError),
+    StoreError(#[from] StoreError),
+}
+
+fn mark_entry_as_read(config: &Config, feed_name: String, entry_id: String) -> Result<(), MarkEntryAsReadError> {
+    return open_store(config)
+           .and_then(|store| store.set_read(&feed_name, &entry_id, true))
+           .map_err(MarkEntryAsReadError::StoreError);
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ReadStateChange {
+    pub feed: String,
+    pub id: String,
+    pub read: bool,
+}
+
+pub fn set_read_states(config: &Config, changes: Vec<ReadStateChange>) -> Result<Vec<ReadStateChange>, StoreError> {
+    // Apply a batch of read state changes in a single modification, returning
+    // the changes for which no matching entry was found.
+    // If the same entry is changed more than once the last change wins.
+
+    // Read states by feed and then id, to look up each entry in
+    let mut pending: HashMap<String, HashMap<String, bool>> = HashMap::new();
+    for change in changes {
+        pending.entry(change.feed).or_default().insert(change.id, change.read);
+    }
+    let mut found: HashSet<(String, String)> = HashSet::new();
+
+    let modifier = |mut entries: Vec<Entry>| -> Vec<Entry> {
+        for entry in entries.iter_mut() {
+            if let Some(read) = pending.get(&entry.feed).and_then(|feed_changes| feed_changes.get(&entry.id)) {
+                entry.set_read(*read);
+                found.insert((entry.feed.clone(), entry.id.clone()));
+            }
+        }
+        return entries;
+    };
+    modify_database(config, modifier)?;
+
+    let mut missing: Vec<ReadStateChange> = Vec::new();
+    for (feed, feed_changes) in pending {
+        for (id, read) in feed_changes {
+            if !found.contains(&(feed.clone(), id.clone())) {
+                missing.push(ReadStateChange{ feed: feed.clone(), id, read });
+            }
+        }
+    }
+    missing.sort_by(|a, b| (&a.feed, &a.id).cmp(&(&b.feed, &b.id)));
+    return Ok(missing);
+}
+
+pub fn set_starred(config: &Config, feed_name: &str, entry_ids: Vec<String>, starred: bool) -> Result<Vec<String>, StoreError> {
+    // Star or unstar entries of a feed in a single modification, returning
+    // the ids for which no entry was found.
+
+    let ids: HashSet<&str> = entry_ids.iter().map(|entry_id| entry_id.as_str()).collect();
+    let mut found: HashSet<String> = HashSet::new();
+    let modifier = |mut entries: Vec<Entry>, _: &mut SeenIds| -> Vec<Entry> {
+        for entry in entries.iter_mut().filter(|entry| entry.feed == feed_name && ids.contains(entry.id.as_str())) {
+            entry.starred = starred;
+            found.insert(entry.id.clone());
+        }
+        return entries;
+    };
+    open_store(config)?.modify_feed(feed_name, Box::new(modifier))?;
+
+    let mut missing: Vec<String> = entry_ids.into_iter().filter(|id| !found.contains(id)).collect();
+    missing.sort();
+    missing.dedup();
+    return Ok(missing);
+}
+
+#[derive(Error, Debug)]
+pub enum RenameFeedError {
     #[error(transparent)]
-    ModifyDatabaseError(#[from] ModifyDatabaseError),
+    FeedDirError(#[from] FeedDirError),
+    #[error("Invalid feed name: {name}")]
+    InvalidName {
+        name: String,
+    },
+    #[error("Feed already exists: {name}")]
+    FeedExists {
+        name: String,
+    },
+    #[error("Failed to rename feed directory: {source}: {path}")]
+    RenameError {
+        source: io::Error,
+        path: PathBuf,
+    },
+    #[error("Failed to update database: {source}")]
+    DatabaseError {
+        source: StoreError,
+    },
+}
+
+pub fn rename_feed(config: &Config, feed_name: &str, new_feed_name: &str) -> Result<(), RenameFeedError> {
+    // Rename a feed's directory, and move its entries and seen ids to the new
+    // name in a single modification. If the database can't be updated the
+    // directory is renamed back, so the entries aren't orphaned.
+
+    // Feed directories are listed by their sanitized names, so a name the
+    // sanitizer would change couldn't be found again
+    if new_feed_name.is_empty() || new_feed_name.contains('/') || new_feed_name == "." || new_feed_name == ".."
+        || new_feed_name == DELETED_FEEDS_DIR
+        || sanitize(new_feed_name.to_string()) != new_feed_name
+    {
+        return Err(RenameFeedError::InvalidName{ name: new_feed_name.to_string() });
+    }
+    let feed_dir = get_feed_dir(config, feed_name.to_string())?;
+    let new_feed_dir = feed_dir.with_file_name(new_feed_name);
+    if fs::symlink_metadata(&new_feed_dir).is_ok() {
+        return Err(RenameFeedError::FeedExists{ name: new_feed_name.to_string() });
+    }
+    let store = open_store(config).map_err(|e| RenameFeedError::DatabaseError{ source: e })?;
+
+    fs::rename(&feed_dir, &new_feed_dir)
+        .map_err(|e| RenameFeedError::RenameError{ source: e, path: feed_dir.clone() })?;
+
+    let modifier = |entries: Vec<Entry>, seen: &mut SeenIds| -> Vec<Entry> {
+        let mut index = EntryIndex::new(entries);
+        let entry_ids: Vec<String> = index.feed_entries(feed_name).map(|entry| entry.id.clone()).collect();
+        for entry_id in entry_ids {
+            index.modify_entry(feed_name, &entry_id, |entry| entry.feed = new_feed_name.to_string());
+        }
+        seen.rename_feed(feed_name, new_feed_name);
+        return index.into_entries();
+    };
+    if let Err(e) = store.modify_seen(Box::new(modifier)) {
+        // Roll back, leaving the feed as it was
+        if let Err(rename_error) = fs::rename(&new_feed_dir, &feed_dir) {
+            return Err(RenameFeedError::RenameError{ source: rename_error, path: new_feed_dir });
+        }
+        return Err(RenameFeedError::DatabaseError{ source: e });
+    }
+    return Ok(());
 }
 
-fn mark_entry_as_read(feed_name: String, entry_id: String) -> Result<(), MarkEntryAsReadError> {
-    let modifier = |entries: Vec<Entry>| -> Vec<Entry> {
-        let mut modified_entries: Vec<Entry> = Vec::new();
-        for mut entry in entries {
-            if entry.feed == feed_name && entry.id == entry_id {
-                entry.read = true;
+#[derive(Error, Debug)]
+pub enum MergeFeedsError {
+    #[error(transparent)]
+    FeedDirError(#[from] FeedDirError),
+    #[error("Cannot merge a feed into itself: {name}")]
+    SameFeed {
+        name: String,
+    },
+    #[error("Failed to update database: {source}")]
+    DatabaseError {
+        source: StoreError,
+    },
+    #[error("Failed to delete feed configuration: {source}: {path}")]
+    RemoveError {
+        source: io::Error,
+        path: PathBuf,
+    },
+}
+
+pub fn merge_feeds(config: &Config, feed_name: &str, destination_feed_name: &str) -> Result<(), MergeFeedsError> {
+    // Move a feed's entries and seen ids into another feed, then delete the
+    // first feed. Entries in both feeds are kept as they are in the
+    // destination, but read or starred if they were in either.
+
+    if feed_name == destination_feed_name {
+        return Err(MergeFeedsError::SameFeed{ name: feed_name.to_string() });
+    }
+    let feed_dir = get_feed_dir(config, feed_name.to_string())?;
+    get_feed_dir(config, destination_feed_name.to_string())?;
+
+    let modifier = |entries: Vec<Entry>, seen: &mut SeenIds| -> Vec<Entry> {
+        let mut index = EntryIndex::new(entries);
+        for entry in index.remove_feed(feed_name) {
+            let merged = index.modify_entry(destination_feed_name, &entry.id, |existing| existing.merge_duplicate(&entry));
+            if !merged {
+                index.insert(Entry { feed: destination_feed_name.to_string(), ..entry });
             }
-            modified_entries.push(entry);
         }
-        return modified_entries;
+        seen.rename_feed(feed_name, destination_feed_name);
+        return index.into_entries();
     };
-    let database_path = get_database_path()
-                        .map_err(|e| MarkEntryAsReadError::DatabasePathError(e))?;
-    return modify_database(modifier, database_path)
-           .map_err(|e| MarkEntryAsReadError::ModifyDatabaseError(e));
+    open_store(config)
+        .and_then(|store| store.modify_seen(Box::new(modifier)))
+        .map_err(|e| MergeFeedsError::DatabaseError{ source: e })?;
+
+    return fs::remove_dir_all(&feed_dir).map_err(|e| MergeFeedsError::RemoveError{ source: e, path: feed_dir });
 }
 
 #[derive(Error, Debug)]
@@ -624,63 +1974,454 @@ pub enum EntryReadError {
     },
 }
 
-pub fn read_entry(feed_name: String, entry: Entry) -> Result<(), EntryReadError> {
-    let feed_dir_path = get_feed_dir(feed_name.clone())?;
+pub fn read_entry(config: &Config, feed_name: String, entry: Entry) -> Result<(), EntryReadError> {
+    let feed_dir_path = get_feed_dir(config, feed_name.clone())?;
 
     let exec_path = feed_dir_path.clone().join("open");
-    Command::new(exec_path.clone())
+    feed_command(&feed_dir_path, &feed_name, "open", config.open.clone())
         .env("TITLE", entry.title.as_str())
         .env("LINK", entry.link.as_str())
+        .env("FEED", entry.feed.as_str())
+        .env("ID", entry.id.as_str())
         .status()
         .map_err(|e| EntryReadError::ExecError{ source: e, path: exec_path })?;
     
-    return mark_entry_as_read(entry.feed, entry.id).map_err(|e| EntryReadError::MarkEntryAsReadError(e));
+    return mark_entry_as_read(config, entry.feed, entry.id).map_err(EntryReadError::MarkEntryAsReadError);
 }
 
 #[derive(Error, Debug)]
 pub enum GetEntriesError {
     #[error(transparent)]
-    DatabasePathError(#[from] DatabasePathError),
-    #[error(transparent)]
-    DatabaseReadError(#[from] DatabaseReadError),
+    StoreError(#[from] StoreError),
 }
 
-pub fn get_feed_entries(feed_name: String) -> Result<Vec<Entry>, GetEntriesError> {
-    let database_path = get_database_path()
-        .map_err(|e| GetEntriesError::DatabasePathError(e))?;
-    let entries = read_entries(database_path)
-        .map_err(|e| GetEntriesError::DatabaseReadError(e))?;
+pub fn load_index(config: &Config, feed_names: &[String]) -> Result<EntryIndex, GetEntriesError> {
+    // Index of the entries of the given feeds, or of all feeds if none are
+    // given.
 
-    let mut feed_entries = Vec::new();
-    for entry in entries {
-        if entry.feed == feed_name {
-            feed_entries.push(entry);
-        }
+    let store = open_store(config)?;
+    if feed_names.is_empty() {
+        return Ok(EntryIndex::new(store.entries()?));
     }
+    let mut entries = Vec::new();
+    for feed_name in feed_names {
+        entries.extend(store.feed_entries(feed_name)?);
+    }
+    return Ok(EntryIndex::new(entries));
+}
 
-    // Sort in date order, falling back to ID, so that when reading
+pub fn get_feed_entries(config: &Config, feed_name: String) -> Result<Vec<Entry>, GetEntriesError> {
+    // Entries in date order, falling back to ID, so that when reading
     // entries the oldest is opened first.
-    feed_entries.sort_by(|a, b| (&a.updated, &a.id).cmp(&(&b.updated, &b.id)));
-    return Ok(feed_entries);
+    let index = load_index(config, std::slice::from_ref(&feed_name))?;
+    return Ok(index.feed_entries(&feed_name).cloned().collect());
 }
 
-pub fn count_unread_entries() -> Result<HashMap<String, u32>, GetEntriesError> {
-    let database_path = get_database_path()
-        .map_err(|e| GetEntriesError::DatabasePathError(e))?;
-    let entries = read_entries(database_path)
-        .map_err(|e| GetEntriesError::DatabaseReadError(e))?;
+pub fn get_entries(config: &Config, feed_names: &[String], read: Option<bool>) -> Result<Vec<Entry>, GetEntriesError> {
+    // Entries of the given feeds (or all feeds, if none are given), which
+    // are read or unread if specified, by feed and then date.
 
-    let mut feed_entries: HashMap<String, u32> = HashMap::new();
-    for entry in entries {
-        if !entry.read {
-            let count = match feed_entries.get(&entry.feed) {
-                Some(i) => i,
-                None => &0,
-            };
-            feed_entries.insert(entry.feed.clone(), count + 1);
+    let index = load_index(config, feed_names)?;
+    let mut entries = Vec::new();
+    for feed_name in index.feeds() {
+        entries.extend(index.feed_entries(feed_name)
+            .filter(|entry| read.is_none() || read == Some(entry.read))
+            .cloned());
+    }
+    return Ok(entries);
+}
+
+pub fn get_read_entries(config: &Config) -> Result<Vec<(DateTime<Utc>, Entry)>, GetEntriesError> {
+    // Read entries with a known read time, most recently read first.
+
+    let mut read_entries = Vec::new();
+    for entry in load_index(config, &[])?.into_entries() {
+        let read_at = entry.read_at.as_deref().and_then(|read_at| DateTime::parse_from_rfc3339(read_at).ok());
+        if let (true, Some(read_at)) = (entry.read, read_at) {
+            read_entries.push((read_at.with_timezone(&Utc), entry));
         }
     }
+    read_entries.sort_by(|a, b| (&b.0, &b.1.feed, &b.1.id).cmp(&(&a.0, &a.1.feed, &a.1.id)));
+    return Ok(read_entries);
+}
 
-    return Ok(feed_entries);
+pub fn count_unread_entries(config: &Config) -> Result<HashMap<String, u32>, GetEntriesError> {
+    return Ok(load_index(config, &[])?.unread_counts());
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_database(dir: &Path, contents: &str) -> PathBuf {
+        let path = dir.join("feedutils.tsv");
+        fs::write(&path, contents).unwrap();
+        return path;
+    }
+
+    fn entry(title: &str, link: &str) -> Entry {
+        return Entry {
+            feed: "feed".to_string(),
+            id: "1".to_string(),
+            title: title.to_string(),
+            updated: "2020-01-01T00:00:00Z".to_string(),
+            link: link.to_string(),
+            read: false,
+            starred: false,
+            read_at: None,
+            first_seen: None,
+        };
+    }
+
+    #[test]
+    fn escapes_delimiters() {
+        assert_eq!(escape_field("a\tb\nc\rd\\e"), "a\\tb\\nc\\rd\\\\e");
+    }
+
+    #[test]
+    fn unescapes_escaped_fields() {
+        for field in ["plain", "", "tab\there", "line\nbreak", "carriage\rreturn", "back\\slash", "trailing\\",
+                      "\\t", "\\\\n", "\t\n\r\\"] {
+            let escaped = escape_field(field);
+            assert!(!escaped.contains(['\t', '\n', '\r']), "{:?}", escaped);
+            assert_eq!(unescape_field(&escaped), field);
+        }
+    }
+
+    #[test]
+    fn keeps_unknown_and_trailing_escapes() {
+        assert_eq!(unescape_field("a\\qb"), "a\\qb");
+        assert_eq!(unescape_field("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn reads_back_written_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("feedutils.tsv");
+        let entries = vec![entry("Tab\there, line\nbreak\r\n", "http://example.org/a\\b\\")];
+        write_tsv_database(path.clone(), &entries).unwrap();
+        let (read_entries, _) = read_entries(path).unwrap();
+        assert!(read_entries == entries);
+    }
+
+    #[test]
+    fn keeps_literal_backslashes_from_version_2() {
+        // Version 2 fields were written as they were, so a backslash followed
+        // by t or n is not an escape
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_database(dir.path(), concat!(
+            "#version 2\n",
+            "feed\tid\tupdated\ttitle\tlink\tread\n",
+            "feed\t1\t2020-01-01T00:00:00Z\tC:\\new\\temp\\\thttp://example.org/a\\b\tread\n",
+        ));
+        let (entries, _) = read_entries(path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].title, "C:\\new\\temp\\");
+        assert_eq!(entries[0].link, "http://example.org/a\\b");
+        assert!(entries[0].read);
+    }
+
+    #[test]
+    fn upgrades_version_2_without_changing_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_database(dir.path(), concat!(
+            "#version 2\n",
+            "feed\tid\tupdated\ttitle\tlink\tread\n",
+            "feed\t1\t2020-01-01T00:00:00Z\tC:\\new\\temp\\\thttp://example.org/a\\b\tunread\n",
+        ));
+        let store = TsvStore::new(path.clone());
+        let entries = store.entries().unwrap();
+        assert!(fs::read_to_string(&path).unwrap().starts_with(&format!("#version {}\n", DATABASE_VERSION)));
+        assert!(store.entries().unwrap() == entries);
+        assert_eq!(entries[0].title, "C:\\new\\temp\\");
+    }
+
+    #[test]
+    fn maps_columns_by_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_database(dir.path(), concat!(
+            "#version 5\n",
+            "first_seen\tlink\tstarred\tread\tread_at\ttitle\tupdated\tid\tfeed\n",
+            "2020-01-02T00:00:00Z\thttp://example.org/1\tstarred\tread\t2020-01-03T00:00:00Z\tTitle\t2020-01-01T00:00:00Z\t1\tfeed\n",
+        ));
+        let (entries, _) = read_entries(path).unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!((entry.feed.as_str(), entry.id.as_str(), entry.updated.as_str()), ("feed", "1", "2020-01-01T00:00:00Z"));
+        assert_eq!((entry.title.as_str(), entry.link.as_str()), ("Title", "http://example.org/1"));
+        assert!(entry.read && entry.starred);
+        assert_eq!(entry.read_at.as_deref(), Some("2020-01-03T00:00:00Z"));
+        assert_eq!(entry.first_seen.as_deref(), Some("2020-01-02T00:00:00Z"));
+    }
+
+    #[test]
+    fn ignores_unknown_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_database(dir.path(), concat!(
+            "#version 5\n",
+            "#later metadata\n",
+            "feed\tid\textra\tupdated\ttitle\tlink\tread\tstarred\tread_at\tfirst_seen\tlast\n",
+            "feed\t1\tx\t2020-01-01T00:00:00Z\tTitle\thttp://example.org/1\tunread\tunstarred\t\t\ty\n",
+        ));
+        let (entries, _) = read_entries(path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].id.as_str(), entries[0].updated.as_str()), ("1", "2020-01-01T00:00:00Z"));
+        assert_eq!(entries[0].title, "Title");
+        assert!(!entries[0].read && !entries[0].starred);
+        assert_eq!((entries[0].read_at.clone(), entries[0].first_seen.clone()), (None, None));
+    }
+
+    #[test]
+    fn rejects_missing_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_database(dir.path(), concat!(
+            "#version 5\n",
+            "feed\tid\tupdated\ttitle\tread\tstarred\tread_at\tfirst_seen\n",
+        ));
+        match read_entries(path) {
+            Err(DatabaseReadError::MissingColumn{ column }) => assert_eq!(column, "link"),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("missing column not reported"),
+        }
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_database(dir.path(), &format!("#version {}\n{}\n", DATABASE_VERSION + 1, DATABASE_COLUMNS.join("\t")));
+        assert!(matches!(read_entries(path), Err(DatabaseReadError::UnsupportedVersion{ .. })));
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        // Version 1 databases have no version line, and only the original
+        // columns
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_database(dir.path(), concat!(
+            "feed\tid\tupdated\ttitle\tlink\tread\n",
+            "feed\t1\t2020-01-01T00:00:00Z\tFirst\thttp://example.org/1\tread\n",
+            "feed\t2\t2020-01-02T00:00:00Z\tSecond \\o/\thttp://example.org/2\tunread\n",
+        ));
+
+        let mut database = read_raw_database(path.clone(), false).unwrap();
+        assert_eq!(database.version, 1);
+        migrate_database(&mut database);
+        assert_eq!(database.version, DATABASE_VERSION);
+        assert_eq!(database.columns, DATABASE_COLUMNS);
+
+        let store = TsvStore::new(path.clone());
+        let entries = store.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].read && !entries[1].read);
+        assert!(!entries[0].starred && !entries[1].starred);
+        assert_eq!(entries[1].title, "Second \\o/");
+        assert!(entries.iter().all(|entry| entry.read_at.is_none() && entry.first_seen.is_none()));
+
+        // The upgrade is written out, and reads back the same
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with(&format!("#version {}\n", DATABASE_VERSION)));
+        assert!(contents.contains(&(DATABASE_COLUMNS.join("\t") + "\n")));
+        assert!(read_entries(path).unwrap().0 == entries);
+    }
+
+    #[test]
+    fn undo_keeps_later_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TsvStore::new(dir.path().join("feedutils.tsv"));
+        store.initialize().unwrap();
+        let mut first = entry("First", "http://example.org/1");
+        first.feed = "a".to_string();
+        let mut second = entry("Second", "http://example.org/2");
+        second.feed = "b".to_string();
+        store.modify(Box::new(|mut entries| { entries.push(first); entries })).unwrap();
+        store.modify(Box::new(|mut entries| { entries.push(second); entries })).unwrap();
+
+        // Journalled, so the update of b is the latest backup
+        store.set_read("a", "1", true).unwrap();
+        let backup = store.read_backup(1).unwrap();
+        assert_eq!(backup.len(), 1);
+        assert!(backup[0].feed == "a" && backup[0].read && backup[0].read_at.is_some());
+
+        // Folding the journal in doesn't take a backup, but keeps the read
+        store.initialize().unwrap();
+        assert!(!get_sibling_path(&store.path, ".journal").exists());
+        let backup = store.read_backup(1).unwrap();
+        assert!(backup.len() == 1 && backup[0].read);
+
+        // Until the next modification backs it up
+        store.modify(Box::new(|entries| entries.into_iter().filter(|entry| entry.feed == "a").collect())).unwrap();
+        assert!(!get_sibling_path(&store.path, ".reads").exists());
+        let backup = store.read_backup(1).unwrap();
+        assert!(backup.len() == 2 && backup.iter().all(|entry| entry.read == (entry.feed == "a")));
+    }
+
+    #[test]
+    fn ignores_journals_from_other_generations() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_database(dir.path(), &format!(
+            "#version {}\n#generation 3\n{}\nfeed\t1\t2020-01-01T00:00:00Z\tTitle\thttp://example.org/1\tunread\tunstarred\t\t\n",
+            DATABASE_VERSION, DATABASE_COLUMNS.join("\t")));
+        let journal_path = get_sibling_path(&path, ".journal");
+        let store = TsvStore::new(path.clone());
+
+        // Left over from before the database was last rewritten
+        fs::write(&journal_path, "#generation 2\nfeed\t1\tread\t2020-01-02T00:00:00Z\n").unwrap();
+        assert!(!store.entries().unwrap()[0].read);
+
+        fs::write(&journal_path, "#generation 3\nfeed\t1\tread\t2020-01-02T00:00:00Z\n").unwrap();
+        let entries = store.entries().unwrap();
+        assert!(entries[0].read);
+        assert_eq!(entries[0].read_at.as_deref(), Some("2020-01-02T00:00:00Z"));
+
+        // Appending to a stale journal starts it afresh
+        fs::write(&journal_path, "#generation 2\nfeed\t1\tread\t2020-01-02T00:00:00Z\n").unwrap();
+        store.set_read("feed", "1", false).unwrap();
+        let journal = read_journal(&journal_path, false).unwrap().unwrap();
+        assert_eq!((journal.generation, journal.changes.len()), (3, 1));
+        assert!(!store.entries().unwrap()[0].read);
+    }
+
+    #[test]
+    fn compacts_large_journals() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TsvStore::new(dir.path().join("feedutils.tsv"));
+        store.initialize().unwrap();
+        let mut long = entry("Long", "http://example.org/long");
+        long.id = "x".repeat(1000);
+        let id = long.id.clone();
+        store.modify(Box::new(|mut entries| { entries.push(long); entries })).unwrap();
+        let generation = read_raw_database(store.path.clone(), true).unwrap().generation;
+
+        // Each change adds a little over 1000 bytes to the journal, so it is
+        // folded into the database once past JOURNAL_COMPACT_SIZE
+        let journal_path = get_sibling_path(&store.path, ".journal");
+        let mut changes = 0;
+        while journal_path.exists() || changes == 0 {
+            assert!(changes as u64 * 1000 <= JOURNAL_COMPACT_SIZE);
+            store.set_read("feed", &id, changes % 2 == 0).unwrap();
+            changes += 1;
+        }
+        assert!(changes as u64 * 1100 > JOURNAL_COMPACT_SIZE);
+
+        let database = read_raw_database(store.path.clone(), false).unwrap();
+        assert_eq!(database.generation, generation + 1);
+        let (entries, _) = read_entries(store.path.clone()).unwrap();
+        assert_eq!(entries[0].read, changes % 2 == 1);
+    }
+
+    #[test]
+    fn remembers_removed_entries_until_expired() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("feedutils.tsv");
+        let config = Config { database: Some(path.clone()), ..Config::default() };
+        let store = TsvStore::new(path.clone());
+        store.initialize().unwrap();
+        let feed = || vec![entry("Title", "http://example.org/1")];
+        let ids = |store: &TsvStore| -> Vec<(String, bool)> {
+            return store.entries().unwrap().into_iter().map(|entry| (entry.id, entry.read)).collect();
+        };
+
+        merge_feed_entries(&config, "feed", feed()).unwrap();
+        store.set_read("feed", "1", true).unwrap();
+
+        // Read and gone from the feed, so removed but remembered
+        merge_feed_entries(&config, "feed", Vec::new()).unwrap();
+        assert!(ids(&store).is_empty());
+        assert!(store.seen_ids().unwrap().contains("feed", "1"));
+
+        // So not added again when it reappears, even if it was last seen
+        // long ago, which keeps it remembered
+        let seen_path = get_sibling_path(&path, ".seen");
+        fs::write(&seen_path, "feed\t1\t2000-01-01T00:00:00Z\n").unwrap();
+        merge_feed_entries(&config, "feed", feed()).unwrap();
+        assert!(ids(&store).is_empty());
+        assert!(store.seen_ids().unwrap().contains("feed", "1"));
+        assert!(!fs::read_to_string(&seen_path).unwrap().contains("2000-01-01"));
+
+        // Once out of the feed for longer than seen_days it is forgotten, and
+        // comes back unread
+        fs::write(&seen_path, "feed\t1\t2000-01-01T00:00:00Z\n").unwrap();
+        merge_feed_entries(&config, "feed", Vec::new()).unwrap();
+        assert!(!store.seen_ids().unwrap().contains("feed", "1"));
+        merge_feed_entries(&config, "feed", feed()).unwrap();
+        assert_eq!(ids(&store), [("1".to_string(), false)]);
+    }
+
+    fn feeds_config(dir: &Path, feed_names: &[&str]) -> Config {
+        let mut config = Config { feed_dir: Some(dir.join("feeds")), database: Some(dir.join("feedutils.tsv")), ..Config::default() };
+        config.store.lock.wait = LockWait::FailFast;
+        for feed_name in feed_names {
+            fs::create_dir_all(dir.join("feeds").join(feed_name)).unwrap();
+        }
+        TsvStore::new(dir.join("feedutils.tsv")).initialize().unwrap();
+        return config;
+    }
+
+    fn feed_entry(feed: &str, id: &str, title: &str) -> Entry {
+        return Entry { feed: feed.to_string(), id: id.to_string(), ..entry(title, &format!("http://example.org/{}", id)) };
+    }
+
+    #[test]
+    fn renames_feeds_or_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = feeds_config(dir.path(), &["old"]);
+        let path = dir.path().join("feedutils.tsv");
+        let store = TsvStore::new(path.clone());
+        store.modify_seen(Box::new(|mut entries, seen| {
+            entries.push(feed_entry("old", "1", "Kept"));
+            seen.insert("old", "2");
+            entries
+        })).unwrap();
+
+        // The directory is renamed first, so is renamed back if the database
+        // can't be updated
+        let lock = lock_tsv_database(&path, &config.store).unwrap();
+        match rename_feed(&config, "old", "new") {
+            Err(RenameFeedError::DatabaseError{ .. }) => {},
+            result => panic!("unexpected result {:?}", result.err()),
+        }
+        drop(lock);
+        assert!(get_feed_dir(&config, "old".to_string()).is_ok());
+        assert!(get_feed_dir(&config, "new".to_string()).is_err());
+        assert_eq!(store.entries().unwrap()[0].feed, "old");
+
+        rename_feed(&config, "old", "new").unwrap();
+        assert_eq!(get_all_feed_names(&config).unwrap(), ["new"]);
+        assert_eq!(store.entries().unwrap()[0].feed, "new");
+        let seen = store.seen_ids().unwrap();
+        assert!(seen.contains("new", "2") && !seen.contains("old", "2"));
+
+        for name in ["", "a/b", "..", DELETED_FEEDS_DIR, "bell\u{7}"] {
+            assert!(matches!(rename_feed(&config, "new", name), Err(RenameFeedError::InvalidName{ .. })), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn merges_feeds_with_shared_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = feeds_config(dir.path(), &["from", "into"]);
+        let store = TsvStore::new(dir.path().join("feedutils.tsv"));
+        store.modify(Box::new(|mut entries| {
+            let mut shared = feed_entry("from", "1", "From");
+            shared.set_read(true);
+            entries.push(shared);
+            entries.push(feed_entry("from", "2", "Only from"));
+            let mut shared = feed_entry("into", "1", "Into");
+            shared.starred = true;
+            entries.push(shared);
+            entries
+        })).unwrap();
+
+        merge_feeds(&config, "from", "into").unwrap();
+        assert_eq!(get_all_feed_names(&config).unwrap(), ["into"]);
+        let entries = store.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        let shared = entries.iter().find(|entry| entry.id == "1").unwrap();
+        assert_eq!((shared.feed.as_str(), shared.title.as_str()), ("into", "Into"));
+        assert!(shared.read && shared.read_at.is_some() && shared.starred);
+        let moved = entries.iter().find(|entry| entry.id == "2").unwrap();
+        assert_eq!((moved.feed.as_str(), moved.title.as_str()), ("into", "Only from"));
+
+        assert!(matches!(merge_feeds(&config, "into", "into"), Err(MergeFeedsError::SameFeed{ .. })));
+    }
+}
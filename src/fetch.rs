@@ -0,0 +1,121 @@
+// Running fetch programs.
+// Each fetch runs in its own process group, so that it and anything it
+// starts (eg curl from a shell script) can be terminated together if it takes
+// too long or produces too much output.
+
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+// How long to wait after asking a fetch to terminate before killing it
+const TERMINATE_GRACE: time::Duration = time::Duration::from_secs(2);
+
+// How often to check whether a fetch has finished
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(20);
+
+pub struct FetchOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    // Whether the fetch was terminated for taking too long
+    pub timed_out: bool,
+    // Whether the fetch was killed for writing more than the limit
+    pub too_large: bool,
+}
+
+fn signal_group(pid: u32, signal: libc::c_int) {
+    // Only called while the fetch hasn't been reaped, so its pid (and so the
+    // group id) can't have been reused. The group may already be gone, which
+    // is fine.
+    if let Ok(pid) = libc::pid_t::try_from(pid) {
+        // SAFETY: kill has no memory safety requirements; a negative pid
+        // signals the process group.
+        unsafe { libc::kill(-pid, signal) };
+    }
+}
+
+fn has_exited(pid: u32) -> io::Result<bool> {
+    // Check whether the fetch has exited, leaving it to be reaped later.
+    loop {
+        // SAFETY: siginfo_t is plain data, for which all zeroes is valid.
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        // SAFETY: info is valid for writes for the duration of the call.
+        let result = unsafe { libc::waitid(libc::P_PID, pid, &mut info, libc::WEXITED | libc::WNOWAIT | libc::WNOHANG) };
+        if result != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        // SAFETY: waitid succeeded, so si_pid has been set, to zero if the
+        // fetch is still running.
+        return Ok(unsafe { info.si_pid() } != 0);
+    }
+}
+
+fn read_limited<R: Read + Send + 'static>(reader: R, limit: u64, too_large: mpsc::Sender<()>) -> thread::JoinHandle<(Vec<u8>, bool)> {
+    // Read the output in the background, telling the waiting thread if the
+    // fetch writes more than the limit.
+    return thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = reader.take(limit.saturating_add(1)).read_to_end(&mut output);
+        let is_too_large = output.len() as u64 > limit;
+        if is_too_large {
+            output.truncate(limit as usize);
+            let _ = too_large.send(());
+        }
+        return (output, is_too_large);
+    });
+}
+
+fn wait_until(pid: u32, deadline: Option<time::Instant>, too_large: &mpsc::Receiver<()>) -> io::Result<bool> {
+    // Wait for the fetch to exit, without reaping it, killing it if it writes
+    // too much. Returns false if the deadline passes first.
+    loop {
+        if has_exited(pid)? {
+            return Ok(true);
+        }
+        if too_large.try_recv().is_ok() {
+            signal_group(pid, libc::SIGKILL);
+        }
+        if deadline.is_some_and(|deadline| time::Instant::now() >= deadline) {
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+pub fn run_fetch(mut command: Command, timeout: Option<time::Duration>, max_size: u64) -> io::Result<FetchOutput> {
+    // Run the fetch, terminating it once the timeout passes and keeping at
+    // most max_size bytes of its output and errors.
+
+    command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).process_group(0);
+    let mut child = command.spawn()?;
+    let pid = child.id();
+    let missing_pipe = || io::Error::new(io::ErrorKind::BrokenPipe, "fetch output is not piped");
+    let (too_large_sender, too_large) = mpsc::channel();
+    let stdout = read_limited(child.stdout.take().ok_or_else(missing_pipe)?, max_size, too_large_sender.clone());
+    let stderr = read_limited(child.stderr.take().ok_or_else(missing_pipe)?, max_size, too_large_sender);
+
+    // A timeout too long to add to the current time is as good as none
+    let deadline = timeout.and_then(|timeout| time::Instant::now().checked_add(timeout));
+    let timed_out = !wait_until(pid, deadline, &too_large)?;
+    if timed_out {
+        signal_group(pid, libc::SIGTERM);
+        wait_until(pid, Some(time::Instant::now() + TERMINATE_GRACE), &too_large)?;
+    }
+    // Anything the fetch left running in the background would keep the
+    // output open, so is terminated too, before the fetch is reaped
+    signal_group(pid, libc::SIGKILL);
+    let status = child.wait()?;
+
+    let (stdout, stdout_too_large) = stdout.join().unwrap_or_default();
+    let (stderr, _) = stderr.join().unwrap_or_default();
+    return Ok(FetchOutput { status, stdout, stderr, timed_out, too_large: stdout_too_large });
+}
@@ -0,0 +1,155 @@
+// Feed health.
+// Every update of a feed is recorded in a `health` file in its directory:
+// when it was last attempted and last succeeded, how many times in a row it
+// has failed, and the last error. Unlike error.log this is kept after a
+// success, so a feed that has been failing for months can be told apart from
+// one that failed once.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use crate::{error_log, get_feed_dir, Config, FeedDirError, UpdateError};
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FeedHealth {
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    // Failed updates since the last success
+    pub failures: u32,
+    // The error from the last failure, after anything the fetch printed
+    pub last_error: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum HealthError {
+    #[error(transparent)]
+    FeedDirError(#[from] FeedDirError),
+    #[error("{source}: {path}")]
+    IoError {
+        source: io::Error,
+        path: PathBuf,
+    },
+    #[error("Invalid health record: {path}: {source}")]
+    ParseError {
+        source: Box<toml::de::Error>,
+        path: PathBuf,
+    },
+}
+
+fn time_value(table: &toml::Table, name: &str) -> Option<DateTime<Utc>> {
+    let time = table.get(name)?.as_str()?;
+    return DateTime::parse_from_rfc3339(time).ok().map(|time| time.with_timezone(&Utc));
+}
+
+fn read_health_file(path: &Path) -> Result<FeedHealth, HealthError> {
+    // A feed that has never been updated has no record yet.
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(FeedHealth::default()),
+        Err(e) => return Err(HealthError::IoError{ source: e, path: path.to_path_buf() }),
+    };
+    let table: toml::Table = contents.parse()
+        .map_err(|e| HealthError::ParseError{ source: Box::new(e), path: path.to_path_buf() })?;
+    return Ok(FeedHealth {
+        last_attempt: time_value(&table, "last_attempt"),
+        last_success: time_value(&table, "last_success"),
+        failures: table.get("failures").and_then(|failures| failures.as_integer())
+            .and_then(|failures| u32::try_from(failures).ok())
+            .unwrap_or(0),
+        last_error: table.get("last_error").and_then(|error| error.as_str()).map(|error| error.to_string()),
+    });
+}
+
+fn write_health_file(path: &Path, health: &FeedHealth) -> Result<(), HealthError> {
+    let mut table = toml::Table::new();
+    if let Some(last_attempt) = health.last_attempt {
+        table.insert("last_attempt".to_string(), toml::Value::String(last_attempt.to_rfc3339()));
+    }
+    if let Some(last_success) = health.last_success {
+        table.insert("last_success".to_string(), toml::Value::String(last_success.to_rfc3339()));
+    }
+    table.insert("failures".to_string(), toml::Value::Integer(health.failures.into()));
+    if let Some(last_error) = &health.last_error {
+        table.insert("last_error".to_string(), toml::Value::String(last_error.clone()));
+    }
+    return fs::write(path, table.to_string())
+        .map_err(|e| HealthError::IoError{ source: e, path: path.to_path_buf() });
+}
+
+pub fn read_health(config: &Config, feed_name: &str) -> Result<FeedHealth, HealthError> {
+    let feed_dir = get_feed_dir(config, feed_name.to_string())?;
+    return read_health_file(&feed_dir.join("health"));
+}
+
+pub fn record_update(config: &Config, feed_name: &str, result: &Result<(), UpdateError>) -> Result<(), HealthError> {
+    // Add the result of an update to the feed's record.
+    // A damaged record is started again rather than blocking updates.
+
+    let path = get_feed_dir(config, feed_name.to_string())?.join("health");
+    let mut health = match read_health_file(&path) {
+        Err(HealthError::ParseError{ .. }) => FeedHealth::default(),
+        health => health?,
+    };
+    let now = Utc::now();
+    health.last_attempt = Some(now);
+    match result {
+        Ok(()) => {
+            health.last_success = Some(now);
+            health.failures = 0;
+        },
+        Err(e) => {
+            health.failures = health.failures.saturating_add(1);
+            health.last_error = Some(String::from_utf8_lossy(&error_log(e)).trim_end().to_string());
+        },
+    }
+    return write_health_file(&path, &health);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_failures_since_the_last_success() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("feed")).unwrap();
+        let config = Config { feed_dir: Some(dir.path().to_path_buf()), ..Config::default() };
+        let failure = || Err(UpdateError::FetchPanicked{ message: "boom".to_string() });
+        assert_eq!(read_health(&config, "feed").unwrap(), FeedHealth::default());
+
+        record_update(&config, "feed", &Ok(())).unwrap();
+        let first = read_health(&config, "feed").unwrap();
+        assert!(first.last_success.is_some() && first.last_attempt == first.last_success);
+        assert_eq!((first.failures, first.last_error.as_deref()), (0, None));
+
+        record_update(&config, "feed", &failure()).unwrap();
+        record_update(&config, "feed", &failure()).unwrap();
+        let failed = read_health(&config, "feed").unwrap();
+        assert_eq!((failed.last_success, failed.failures), (first.last_success, 2));
+        assert!(failed.last_attempt > first.last_attempt);
+        assert!(failed.last_error.as_deref().is_some_and(|error| error.contains("boom")));
+
+        // The last error is kept for reference after a success
+        record_update(&config, "feed", &Ok(())).unwrap();
+        let recovered = read_health(&config, "feed").unwrap();
+        assert!(recovered.last_success > first.last_success && recovered.last_attempt == recovered.last_success);
+        assert_eq!((recovered.failures, recovered.last_error), (0, failed.last_error));
+    }
+
+    #[test]
+    fn restarts_damaged_records() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("feed")).unwrap();
+        let config = Config { feed_dir: Some(dir.path().to_path_buf()), ..Config::default() };
+        fs::write(dir.path().join("feed").join("health"), "failures = [").unwrap();
+        assert!(matches!(read_health(&config, "feed"), Err(HealthError::ParseError{ .. })));
+
+        record_update(&config, "feed", &Err(UpdateError::FetchPanicked{ message: "boom".to_string() })).unwrap();
+        let health = read_health(&config, "feed").unwrap();
+        assert_eq!((health.failures, health.last_success), (1, None));
+    }
+}
@@ -0,0 +1,218 @@
+// Built-in HTTP fetching.
+// Feeds with a url file and no fetch executable are downloaded directly,
+// following redirects and treating anything but a 200 response (or a 304 when
+// the feed is unchanged) as a failure, rather than needing a curl script per
+// feed.
+
+use std::error::Error;
+use std::io;
+use std::io::Read;
+use std::time;
+
+use crate::{UpdateError, Validators};
+
+// Redirects followed before giving up
+const MAX_REDIRECTS: u32 = 10;
+
+pub fn fetch_url(url: &str, validators: &Validators, timeout: Option<time::Duration>, max_size: u64)
+    -> Result<Option<(Vec<u8>, Validators)>, UpdateError>
+{
+    // Download the feed, keeping at most max_size bytes, along with its new
+    // validators. Returns None if the feed hasn't changed since the
+    // validators were saved.
+
+    let mut agent = ureq::AgentBuilder::new()
+        .redirects(MAX_REDIRECTS)
+        .user_agent(concat!("feedutils/", env!("CARGO_PKG_VERSION")));
+    // As for fetch executables, a timeout too long to add to the current
+    // time is as good as none (where ureq would refuse to make the request)
+    let timeout = timeout.filter(|timeout| time::Instant::now().checked_add(*timeout).is_some());
+    if let Some(timeout) = timeout {
+        agent = agent.timeout(timeout);
+    }
+    let timed_out = |e: &io::Error| -> Option<UpdateError> {
+        // Running out of time is reported as for fetch executables
+        if !matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) {
+            return None;
+        }
+        return timeout.map(|timeout| UpdateError::FetchTimeout{ stderr: Vec::new(), timeout });
+    };
+
+    let mut request = agent.build().get(url);
+    if let Some(etag) = &validators.etag {
+        request = request.set("If-None-Match", etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.set("If-Modified-Since", last_modified);
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, response)) => return Err(UpdateError::HttpStatus {
+            status,
+            reason: response.status_text().to_string(),
+            url: response.get_url().to_string(),
+        }),
+        Err(ureq::Error::Transport(transport)) => {
+            if let Some(e) = transport.source().and_then(|source| source.downcast_ref::<io::Error>()).and_then(timed_out) {
+                return Err(e);
+            }
+            return Err(UpdateError::HttpError {
+                source: Box::new(transport),
+                url: url.to_string(),
+            });
+        },
+    };
+    if response.status() == 304 && !validators.is_empty() {
+        return Ok(None);
+    }
+    if response.status() != 200 {
+        // Other 1xx-3xx responses, eg too many redirects or No Content
+        return Err(UpdateError::HttpStatus {
+            status: response.status(),
+            reason: response.status_text().to_string(),
+            url: response.get_url().to_string(),
+        });
+    }
+
+    let final_url = response.get_url().to_string();
+    let validators = Validators {
+        etag: response.header("ETag").map(|etag| etag.to_string()),
+        last_modified: response.header("Last-Modified").map(|last_modified| last_modified.to_string()),
+    };
+    let mut body = Vec::new();
+    response.into_reader().take(max_size.saturating_add(1)).read_to_end(&mut body)
+        .map_err(|e| timed_out(&e).unwrap_or(UpdateError::HttpReadError{ source: e, url: final_url }))?;
+    if body.len() as u64 > max_size {
+        return Err(UpdateError::FetchTooLarge{ stderr: Vec::new(), limit: max_size });
+    }
+    return Ok(Some((body, validators)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn serve<F>(respond: F) -> String
+        where F: Fn(&str) -> String + Send + 'static
+    {
+        // Answer each request on a local port with the response for its
+        // request line and headers, returning the server's URL.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    request += &(line + "\n");
+                }
+                let _ = stream.write_all(respond(&request).as_bytes());
+            }
+        });
+        return url;
+    }
+
+    fn response(status: &str, headers: &[&str], body: &str) -> String {
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+        for header in headers {
+            response += &format!("{}\r\n", header);
+        }
+        return response + "\r\n" + body;
+    }
+
+    #[test]
+    fn fetches_body_and_validators() {
+        let url = serve(|_| response("200 OK", &["ETag: \"v1\"", "Last-Modified: Sat, 17 Oct 2026 10:00:00 GMT"], "<feed/>"));
+        let (body, validators) = fetch_url(&url, &Validators::default(), None, 1000).unwrap().unwrap();
+        assert_eq!(body, b"<feed/>");
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(validators.last_modified.as_deref(), Some("Sat, 17 Oct 2026 10:00:00 GMT"));
+    }
+
+    #[test]
+    fn follows_redirects() {
+        let url = serve(|request| match request.starts_with("GET /moved ") {
+            true => response("301 Moved Permanently", &["Location: /feed.xml"], ""),
+            false => response("200 OK", &[], "<feed/>"),
+        });
+        let (body, _) = fetch_url(&format!("{}/moved", url), &Validators::default(), None, 1000).unwrap().unwrap();
+        assert_eq!(body, b"<feed/>");
+    }
+
+    #[test]
+    fn fails_on_other_status() {
+        let url = serve(|_| response("404 Not Found", &[], "gone"));
+        match fetch_url(&url, &Validators::default(), None, 1000) {
+            Err(UpdateError::HttpStatus{ status, reason, .. }) => assert_eq!((status, reason.as_str()), (404, "Not Found")),
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn names_the_url_on_connection_failure() {
+        // Nothing is listening once the listener is dropped
+        let url = format!("http://{}/feed.xml", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        match fetch_url(&url, &Validators::default(), None, 1000) {
+            Err(e @ UpdateError::HttpError{ .. }) => assert!(e.to_string().ends_with(&format!(": {}", url)), "{}", e),
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn limits_size() {
+        let url = serve(|_| response("200 OK", &[], "0123456789"));
+        assert!(fetch_url(&url, &Validators::default(), None, 10).unwrap().is_some());
+        match fetch_url(&url, &Validators::default(), None, 9) {
+            Err(UpdateError::FetchTooLarge{ limit, .. }) => assert_eq!(limit, 9),
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn sends_validators_and_accepts_not_modified() {
+        let url = serve(|request| match request.contains("If-None-Match: \"v1\"\n") && request.contains("If-Modified-Since: yesterday\n") {
+            true => response("304 Not Modified", &[], ""),
+            false => response("200 OK", &[], "<feed/>"),
+        });
+        let validators = Validators { etag: Some("\"v1\"".to_string()), last_modified: Some("yesterday".to_string()) };
+        assert!(fetch_url(&url, &validators, None, 1000).unwrap().is_none());
+        assert!(fetch_url(&url, &Validators::default(), None, 1000).unwrap().is_some());
+    }
+
+    #[test]
+    fn fails_on_unrequested_not_modified() {
+        let url = serve(|_| response("304 Not Modified", &[], ""));
+        match fetch_url(&url, &Validators::default(), None, 1000) {
+            Err(UpdateError::HttpStatus{ status, .. }) => assert_eq!(status, 304),
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn times_out() {
+        let url = serve(|_| {
+            thread::sleep(time::Duration::from_secs(2));
+            return response("200 OK", &[], "<feed/>");
+        });
+        let timeout = time::Duration::from_millis(100);
+        match fetch_url(&url, &Validators::default(), Some(timeout), 1000) {
+            Err(UpdateError::FetchTimeout{ timeout: reported, .. }) => assert_eq!(reported, timeout),
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn ignores_timeout_too_long_for_a_deadline() {
+        let url = serve(|_| response("200 OK", &[], "<feed/>"));
+        assert!(fetch_url(&url, &Validators::default(), Some(time::Duration::MAX), 1000).unwrap().is_some());
+    }
+}
@@ -0,0 +1,375 @@
+// In-memory index of entries.
+// Entries are indexed by feed, by id within each feed and by date within each
+// feed, so looking up or changing a single entry doesn't need a scan over the
+// whole database. The original order of the entries is kept, so that an
+// unchanged index gives back exactly the entries it was built from.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
+
+use crate::Entry;
+
+#[derive(Clone, Default)]
+struct FeedIndex {
+    // Positions of the feed's entries, by id
+    ids: HashMap<String, usize>,
+    // Positions of the feed's entries, in (updated, id) order
+    by_date: Vec<usize>,
+    unread: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct EntryIndex {
+    // Entries in their original order; removed entries leave a gap
+    entries: Vec<Option<Entry>>,
+    feeds: BTreeMap<String, FeedIndex>,
+    len: usize,
+}
+
+impl EntryIndex {
+    pub fn new(entries: Vec<Entry>) -> EntryIndex {
+        // Build the index in one pass, sorting each feed afterwards.
+        // Duplicated entries (as reported by feed-fsck) are merged into the
+        // first of them, as feed-fsck repairs them.
+        let mut index = EntryIndex::default();
+        for entry in entries {
+            let position = index.entries.len();
+            let feed_index = index.feeds.entry(entry.feed.clone()).or_default();
+            if let Some(first) = feed_index.ids.get(&entry.id) {
+                if let Some(first) = index.entries[*first].as_mut() {
+                    if !first.read && entry.read {
+                        feed_index.unread -= 1;
+                    }
+                    first.merge_duplicate(&entry);
+                }
+                continue;
+            }
+            feed_index.ids.insert(entry.id.clone(), position);
+            feed_index.by_date.push(position);
+            if !entry.read {
+                feed_index.unread += 1;
+            }
+            index.entries.push(Some(entry));
+            index.len += 1;
+        }
+
+        let entries = &index.entries;
+        for feed_index in index.feeds.values_mut() {
+            feed_index.by_date.sort_by(|a, b| date_key(entries, *a).cmp(&date_key(entries, *b)));
+        }
+        return index;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    fn position(&self, feed_name: &str, entry_id: &str) -> Option<usize> {
+        return self.feeds.get(feed_name).and_then(|feed_index| feed_index.ids.get(entry_id)).copied();
+    }
+
+    pub fn get(&self, feed_name: &str, entry_id: &str) -> Option<&Entry> {
+        return self.position(feed_name, entry_id).and_then(|position| self.entries[position].as_ref());
+    }
+
+    pub fn contains(&self, feed_name: &str, entry_id: &str) -> bool {
+        return self.position(feed_name, entry_id).is_some();
+    }
+
+    pub fn modify_entry<F>(&mut self, feed_name: &str, entry_id: &str, modifier: F) -> bool
+        where F: FnOnce(&mut Entry)
+    {
+        // Change a single entry in place, returning whether it was found.
+        // The modifier may change any field, including the feed and id, in
+        // which case any other entry with the new feed and id is replaced.
+        let position = match self.position(feed_name, entry_id) {
+            Some(position) => position,
+            None => return false,
+        };
+        let entry = match self.entries[position].as_mut() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let (read, updated) = (entry.read, entry.updated.clone());
+        modifier(entry);
+        let rekeyed = entry.feed != feed_name || entry.id != entry_id;
+        let (now_read, now_updated) = (entry.read, entry.updated != updated);
+
+        if rekeyed {
+            // Reindex under the new key, in the same place. The entry no
+            // longer matches its old key, so that is removed by hand, using
+            // its read state from before it was changed.
+            if let Some(feed_index) = self.feeds.get_mut(feed_name) {
+                feed_index.ids.remove(entry_id);
+                feed_index.by_date.retain(|p| *p != position);
+                if !read {
+                    feed_index.unread -= 1;
+                }
+                if feed_index.ids.is_empty() {
+                    self.feeds.remove(feed_name);
+                }
+            }
+            self.len -= 1;
+            if let Some(entry) = self.entries[position].take() {
+                self.remove(&entry.feed, &entry.id);
+                self.insert_at(position, entry);
+            }
+            return true;
+        }
+        let feed_index = match self.feeds.get_mut(feed_name) {
+            Some(feed_index) => feed_index,
+            None => return true,
+        };
+        if read && !now_read {
+            feed_index.unread += 1;
+        } else if !read && now_read {
+            feed_index.unread -= 1;
+        }
+        if now_updated {
+            feed_index.by_date.retain(|p| *p != position);
+            let entries = &self.entries;
+            let key = date_key(entries, position);
+            let at = feed_index.by_date.partition_point(|p| date_key(entries, *p) < key);
+            feed_index.by_date.insert(at, position);
+        }
+        return true;
+    }
+
+    pub fn insert(&mut self, entry: Entry) -> Option<Entry> {
+        // Add an entry, replacing (in the same place) and returning any
+        // existing entry with the same feed and id.
+        if self.contains(&entry.feed, &entry.id) {
+            let (feed_name, entry_id) = (entry.feed.clone(), entry.id.clone());
+            let mut replaced = None;
+            self.modify_entry(&feed_name, &entry_id, |existing| {
+                replaced = Some(std::mem::replace(existing, entry));
+            });
+            return replaced;
+        }
+        let position = self.entries.len();
+        self.entries.push(None);
+        self.insert_at(position, entry);
+        return None;
+    }
+
+    fn insert_at(&mut self, position: usize, entry: Entry) {
+        // Index an entry at an empty position.
+        let feed_index = self.feeds.entry(entry.feed.clone()).or_default();
+        feed_index.ids.insert(entry.id.clone(), position);
+        if !entry.read {
+            feed_index.unread += 1;
+        }
+        self.entries[position] = Some(entry);
+        self.len += 1;
+
+        let entries = &self.entries;
+        let key = date_key(entries, position);
+        let at = feed_index.by_date.partition_point(|p| date_key(entries, *p) < key);
+        feed_index.by_date.insert(at, position);
+    }
+
+    pub fn remove(&mut self, feed_name: &str, entry_id: &str) -> Option<Entry> {
+        let feed_index = self.feeds.get_mut(feed_name)?;
+        let position = feed_index.ids.remove(entry_id)?;
+        feed_index.by_date.retain(|p| *p != position);
+        let entry = self.entries[position].take()?;
+        if !entry.read {
+            feed_index.unread -= 1;
+        }
+        if feed_index.ids.is_empty() {
+            self.feeds.remove(feed_name);
+        }
+        self.len -= 1;
+        return Some(entry);
+    }
+
+    pub fn remove_feed(&mut self, feed_name: &str) -> Vec<Entry> {
+        // Remove all of a feed's entries, returning them in date order.
+        let feed_index = match self.feeds.remove(feed_name) {
+            Some(feed_index) => feed_index,
+            None => return Vec::new(),
+        };
+        let entries: Vec<Entry> = feed_index.by_date.iter()
+            .filter_map(|position| self.entries[*position].take())
+            .collect();
+        self.len -= entries.len();
+        return entries;
+    }
+
+    pub fn feeds(&self) -> impl Iterator<Item = &str> + '_ {
+        // Feeds with at least one entry, by name.
+        return self.feeds.keys().map(|feed_name| feed_name.as_str());
+    }
+
+    pub fn feed_entries(&self, feed_name: &str) -> impl Iterator<Item = &Entry> + '_ {
+        // A feed's entries, in date order.
+        return self.feed_range(feed_name, ..);
+    }
+
+    pub fn feed_range<'a, R>(&'a self, feed_name: &str, range: R) -> impl Iterator<Item = &'a Entry> + 'a
+        where R: RangeBounds<&'a str>
+    {
+        // A feed's entries updated within the range of dates, in date order.
+        // Dates compare as strings, which orders RFC 3339 dates in the same
+        // timezone correctly.
+        let by_date: &[usize] = self.feeds.get(feed_name).map(|feed_index| feed_index.by_date.as_slice()).unwrap_or_default();
+        let updated = |position: &usize| self.entries[*position].as_ref().map(|entry| entry.updated.as_str()).unwrap_or_default();
+        let start = match range.start_bound() {
+            Bound::Included(start) => by_date.partition_point(|p| updated(p) < *start),
+            Bound::Excluded(start) => by_date.partition_point(|p| updated(p) <= *start),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => by_date.partition_point(|p| updated(p) <= *end),
+            Bound::Excluded(end) => by_date.partition_point(|p| updated(p) < *end),
+            Bound::Unbounded => by_date.len(),
+        };
+        return by_date[start..end.max(start)].iter().filter_map(|position| self.entries[*position].as_ref());
+    }
+
+    pub fn unread_count(&self, feed_name: &str) -> usize {
+        return self.feeds.get(feed_name).map(|feed_index| feed_index.unread).unwrap_or(0);
+    }
+
+    pub fn unread_counts(&self) -> HashMap<String, u32> {
+        // Unread entries per feed, for feeds with any unread.
+        return self.feeds.iter()
+            .filter(|(_, feed_index)| feed_index.unread > 0)
+            .map(|(feed_name, feed_index)| (feed_name.clone(), feed_index.unread as u32))
+            .collect();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> + '_ {
+        // All entries, in their original order.
+        return self.entries.iter().flatten();
+    }
+
+    pub fn into_entries(self) -> Vec<Entry> {
+        // All entries, in their original order, with added entries last.
+        return self.entries.into_iter().flatten().collect();
+    }
+}
+
+fn date_key(entries: &[Option<Entry>], position: usize) -> (&str, &str) {
+    return match &entries[position] {
+        Some(entry) => (entry.updated.as_str(), entry.id.as_str()),
+        None => ("", ""),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(feed: &str, id: &str, updated: &str, read: bool) -> Entry {
+        return Entry {
+            feed: feed.to_string(),
+            id: id.to_string(),
+            updated: updated.to_string(),
+            title: format!("Entry {}", id),
+            link: format!("http://example.org/{}", id),
+            read,
+            starred: false,
+            read_at: None,
+            first_seen: None,
+        };
+    }
+
+    fn ids<'a>(entries: impl Iterator<Item = &'a Entry>) -> Vec<String> {
+        return entries.map(|entry| format!("{}/{}", entry.feed, entry.id)).collect();
+    }
+
+    #[test]
+    fn merges_duplicates_into_the_first() {
+        let mut duplicate = entry("a", "1", "2020-01-03", true);
+        duplicate.starred = true;
+        duplicate.title = "Duplicate".to_string();
+        let index = EntryIndex::new(vec![
+            entry("a", "1", "2020-01-01", false),
+            entry("a", "2", "2020-01-02", false),
+            duplicate,
+        ]);
+        assert_eq!(index.len(), 2);
+        assert_eq!(ids(index.iter()), ["a/1", "a/2"]);
+        let first = index.get("a", "1").unwrap();
+        assert!(first.read && first.starred);
+        assert_eq!((first.title.as_str(), first.updated.as_str()), ("Entry 1", "2020-01-01"));
+        assert_eq!(index.unread_count("a"), 1);
+    }
+
+    #[test]
+    fn rekeys_modified_entries() {
+        let mut index = EntryIndex::new(vec![
+            entry("a", "1", "2020-01-01", false),
+            entry("a", "2", "2020-01-02", false),
+            entry("b", "1", "2020-01-03", true),
+        ]);
+
+        // Moving an unread entry to another feed and marking it read
+        // counts it as unread in the old feed only
+        assert!(index.modify_entry("a", "1", |entry| {
+            entry.feed = "b".to_string();
+            entry.id = "3".to_string();
+            entry.read = true;
+        }));
+        assert_eq!((index.unread_count("a"), index.unread_count("b")), (1, 0));
+        assert!(!index.contains("a", "1"));
+        assert_eq!(ids(index.iter()), ["b/3", "a/2", "b/1"]);
+        assert_eq!(ids(index.feed_entries("b")), ["b/3", "b/1"]);
+
+        // Moving onto an existing entry replaces it, and an emptied feed
+        // goes away
+        assert!(index.modify_entry("a", "2", |entry| {
+            entry.feed = "b".to_string();
+            entry.id = "1".to_string();
+        }));
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.feeds().collect::<Vec<_>>(), ["b"]);
+        assert_eq!(ids(index.iter()), ["b/3", "b/1"]);
+        assert_eq!(index.get("b", "1").unwrap().updated, "2020-01-02");
+        assert_eq!(index.unread_counts(), HashMap::from([("b".to_string(), 1)]));
+
+        assert!(!index.modify_entry("a", "2", |_| {}));
+    }
+
+    #[test]
+    fn selects_feed_ranges() {
+        let index = EntryIndex::new(vec![
+            entry("a", "3", "2020-01-03", false),
+            entry("a", "1", "2020-01-01", false),
+            entry("b", "2", "2020-01-02", false),
+            entry("a", "2", "2020-01-02", false),
+        ]);
+        assert_eq!(ids(index.feed_range("a", ..)), ["a/1", "a/2", "a/3"]);
+        assert_eq!(ids(index.feed_range("a", "2020-01-02"..)), ["a/2", "a/3"]);
+        assert_eq!(ids(index.feed_range("a", .."2020-01-02")), ["a/1"]);
+        assert_eq!(ids(index.feed_range("a", ..="2020-01-02")), ["a/1", "a/2"]);
+        assert_eq!(ids(index.feed_range("a", (Bound::Excluded("2020-01-01"), Bound::Excluded("2020-01-03")))), ["a/2"]);
+        assert_eq!(ids(index.feed_range("a", "2020-01-03".."2020-01-01")), Vec::<String>::new());
+        assert_eq!(ids(index.feed_range("c", ..)), Vec::<String>::new());
+    }
+
+    #[test]
+    fn replaces_in_place_and_appends() {
+        let mut index = EntryIndex::new(vec![
+            entry("a", "1", "2020-01-01", false),
+            entry("a", "2", "2020-01-02", false),
+        ]);
+        let replaced = index.insert(entry("a", "1", "2020-01-03", true)).unwrap();
+        assert_eq!(replaced.updated, "2020-01-01");
+        assert!(index.insert(entry("b", "1", "2020-01-01", false)).is_none());
+        assert!(index.remove("a", "2").is_some());
+        assert!(index.insert(entry("a", "2", "2020-01-04", false)).is_none());
+
+        assert_eq!(index.len(), 3);
+        assert_eq!(ids(index.feed_entries("a")), ["a/1", "a/2"]);
+        assert_eq!((index.unread_count("a"), index.unread_count("b")), (1, 1));
+        let entries = index.into_entries();
+        assert_eq!(ids(entries.iter()), ["a/1", "b/1", "a/2"]);
+        assert_eq!(entries[0].updated, "2020-01-03");
+    }
+}
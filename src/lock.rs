@@ -0,0 +1,374 @@
+// Database lockfiles.
+// A lockfile is created exclusively next to the database and records which
+// process holds it, so that a lock left behind by a crashed process can be
+// recognised and taken over.
+// Optionally, processes can first queue for the lockfile using a kernel
+// advisory lock (flock) on a separate file. The lockfile is still taken
+// afterwards, so processes using either method still exclude each other.
+
+use std::ffi::CStr;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockWait {
+    // Give up immediately if the database is locked
+    FailFast,
+    // Retry until the timeout expires
+    Timeout(time::Duration),
+    // Wait for as long as it takes
+    Block,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockMethod {
+    // Poll for the lockfile with increasing delays
+    LockFile,
+    // Queue using flock before taking the lockfile
+    Flock,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LockOptions {
+    pub wait: LockWait,
+    pub method: LockMethod,
+}
+
+impl Default for LockOptions {
+    fn default() -> LockOptions {
+        return LockOptions {
+            wait: LockWait::Timeout(time::Duration::from_millis(2000)), // 2s seems more than long enough
+            method: LockMethod::LockFile,
+        };
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+    pub time: String,
+}
+
+impl LockOwner {
+    fn current() -> LockOwner {
+        return LockOwner {
+            pid: process::id(),
+            host: get_hostname(),
+            time: chrono::offset::Utc::now().to_rfc3339(),
+        };
+    }
+
+    fn parse(contents: &str) -> Option<LockOwner> {
+        let mut pid = None;
+        let mut host = None;
+        let mut time = None;
+        for line in contents.lines() {
+            match line.split_once(' ') {
+                Some(("pid", value)) => pid = value.parse().ok(),
+                Some(("host", value)) => host = Some(value.to_string()),
+                Some(("time", value)) => time = Some(value.to_string()),
+                _ => {},
+            }
+        }
+        return Some(LockOwner { pid: pid?, host: host?, time: time? });
+    }
+
+    fn format(&self) -> String {
+        return format!("pid {}\nhost {}\ntime {}\n", self.pid, self.host, self.time);
+    }
+
+    pub fn is_alive(&self) -> Option<bool> {
+        // Whether the owning process is still running, if that can be told.
+        // We can only check processes on this host; the PID may have been
+        // reused since, in which case we err on the side of it being alive.
+
+        if self.host != get_hostname() {
+            return None;
+        }
+        let pid = match libc::pid_t::try_from(self.pid) {
+            Ok(pid) if pid > 0 => pid,
+            _ => return None,
+        };
+        // SAFETY: signal 0 only checks whether the process exists.
+        if unsafe { libc::kill(pid, 0) } == 0 {
+            return Some(true);
+        }
+        match io::Error::last_os_error().raw_os_error() {
+            Some(libc::ESRCH) => Some(false),
+            // EPERM means it exists but belongs to someone else
+            _ => Some(true),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LockState {
+    Unlocked,
+    Owned(LockOwner),
+    // Locked, but we can't tell by whom; eg if the owner crashed before
+    // recording itself.
+    Unknown,
+}
+
+fn get_hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer length is passed, and the last byte is never written
+    // so the result is always nul terminated.
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len() - 1) };
+    if result != 0 {
+        return String::new();
+    }
+    return CStr::from_bytes_until_nul(&buffer)
+        .map(|hostname| hostname.to_string_lossy().into_owned())
+        .unwrap_or_default();
+}
+
+pub fn get_lock_state(lockfile_path: &Path) -> io::Result<LockState> {
+    match fs::read_to_string(lockfile_path) {
+        Ok(contents) => match LockOwner::parse(&contents) {
+            Some(owner) => Ok(LockState::Owned(owner)),
+            None => Ok(LockState::Unknown),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(LockState::Unlocked),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn break_lock(lockfile_path: &Path, expected: &LockState) -> io::Result<bool> {
+    // Remove a lockfile, but only if it is still in the expected state.
+    // Another process may have broken the lock and taken it in the meantime,
+    // so move the lockfile aside first and put it back if it isn't the one we
+    // expected. Returns whether the lock was removed.
+
+    let mut file_name = lockfile_path.file_name().unwrap().to_os_string();
+    file_name.push(format!(".stale.{}", process::id()));
+    let stale_path: PathBuf = lockfile_path.with_file_name(file_name);
+
+    match fs::rename(lockfile_path, &stale_path) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    }
+
+    let state = get_lock_state(&stale_path)?;
+    if &state != expected {
+        // Not ours to break; restore it unless someone has locked since
+        if let Err(e) = fs::hard_link(&stale_path, lockfile_path) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+        }
+        fs::remove_file(&stale_path)?;
+        return Ok(false);
+    }
+
+    fs::remove_file(&stale_path)?;
+    return Ok(true);
+}
+
+// Removes the lockfile once dropped.
+pub struct LockGuard {
+    path: PathBuf,
+    // Held until the guard is dropped, after the lockfile is removed
+    _queue: Option<fs::File>,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            eprintln!("Unable to delete lockfile: {err}");
+        }
+    }
+}
+
+fn create_lockfile(lockfile_path: &Path) -> io::Result<()> {
+    let mut f = OpenOptions::new().write(true)
+                                  .create_new(true)
+                                  .open(lockfile_path)?;
+    if let Err(e) = f.write_all(LockOwner::current().format().as_bytes()) {
+        let _ = fs::remove_file(lockfile_path);
+        return Err(e);
+    }
+    return Ok(());
+}
+
+fn retry<F>(wait: LockWait, mut attempt: F) -> io::Result<()>
+    where F: FnMut() -> io::Result<bool>
+{
+    // Call attempt until it succeeds, waits out the given wait or fails.
+    // Failing to get the lock is reported with an io::Error of kind
+    // WouldBlock.
+
+    let start = time::Instant::now();
+    let mut delay = time::Duration::from_millis(50);
+    let max_delay = time::Duration::from_millis(1000);
+
+    loop {
+        if attempt()? {
+            return Ok(());
+        }
+
+        let delay_until_timeout = match wait {
+            LockWait::FailFast => time::Duration::ZERO,
+            LockWait::Timeout(timeout) => timeout.saturating_sub(start.elapsed()),
+            LockWait::Block => delay,
+        };
+        if delay_until_timeout.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "database is locked"));
+        }
+        thread::sleep(delay.min(delay_until_timeout));
+        delay = (delay * 2).min(max_delay);
+    }
+}
+
+fn queue_flock(queue_path: &Path, wait: LockWait) -> io::Result<fs::File> {
+    // Take an exclusive flock on the queue file. Blocking waits are queued by
+    // the kernel; otherwise we have to poll.
+
+    let f = OpenOptions::new().write(true).create(true).truncate(false).open(queue_path)?;
+    let fd = f.as_raw_fd();
+
+    if wait == LockWait::Block {
+        // SAFETY: fd is a valid open file descriptor for the duration of the call.
+        if unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(f);
+    }
+
+    retry(wait, || {
+        // SAFETY: fd is a valid open file descriptor for the duration of the call.
+        if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::WouldBlock {
+            return Ok(false);
+        }
+        return Err(e);
+    })?;
+    return Ok(f);
+}
+
+pub fn acquire(lockfile_path: &Path, options: &LockOptions) -> io::Result<LockGuard> {
+    // Attempt to acquire a lockfile, waiting as configured.
+    // Locks held by processes which no longer exist are taken over.
+
+    let queue = match options.method {
+        LockMethod::LockFile => None,
+        LockMethod::Flock => {
+            let mut file_name = lockfile_path.file_name().unwrap().to_os_string();
+            file_name.push(".queue");
+            Some(queue_flock(&lockfile_path.with_file_name(file_name), options.wait)?)
+        },
+    };
+
+    retry(options.wait, || {
+        match create_lockfile(lockfile_path) {
+            Ok(()) => return Ok(true),
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            Err(_) => {},
+        }
+
+        let state = get_lock_state(lockfile_path)?;
+        if let LockState::Owned(owner) = &state {
+            if owner.is_alive() == Some(false) {
+                eprintln!("Removing stale lockfile left by process {}: {}",
+                          owner.pid, lockfile_path.display());
+                break_lock(lockfile_path, &state)?;
+                return create_lockfile(lockfile_path).map(|_| true).or_else(|e| {
+                    if e.kind() == io::ErrorKind::AlreadyExists { Ok(false) } else { Err(e) }
+                });
+            }
+        }
+        return Ok(false);
+    })?;
+
+    return Ok(LockGuard { path: lockfile_path.to_path_buf(), _queue: queue });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAIL_FAST: LockOptions = LockOptions { wait: LockWait::FailFast, method: LockMethod::LockFile };
+
+    fn dead_pid() -> u32 {
+        // A process which has exited and been reaped
+        let mut child = process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        return child.id();
+    }
+
+    fn owner(pid: u32, host: &str) -> LockOwner {
+        return LockOwner { pid, host: host.to_string(), time: "2020-01-01T00:00:00+00:00".to_string() };
+    }
+
+    fn assert_locked(path: &Path) {
+        match acquire(path, &FAIL_FAST) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+            Ok(_) => panic!("lock taken over"),
+        }
+    }
+
+    #[test]
+    fn takes_over_locks_of_dead_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("feedutils.tsv.lock");
+        let stale = owner(dead_pid(), &get_hostname());
+        fs::write(&path, stale.format()).unwrap();
+        assert_eq!(stale.is_alive(), Some(false));
+
+        let guard = acquire(&path, &FAIL_FAST).unwrap();
+        match get_lock_state(&path).unwrap() {
+            LockState::Owned(owner) => assert_eq!(owner.pid, process::id()),
+            state => panic!("unexpected state {:?}", state),
+        }
+        drop(guard);
+        assert_eq!(get_lock_state(&path).unwrap(), LockState::Unlocked);
+    }
+
+    #[test]
+    fn keeps_locks_of_live_or_unknown_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("feedutils.tsv.lock");
+
+        // Running here, or maybe running elsewhere
+        for owner in [owner(process::id(), &get_hostname()), owner(dead_pid(), "elsewhere.invalid")] {
+            fs::write(&path, owner.format()).unwrap();
+            assert_ne!(owner.is_alive(), Some(false));
+            assert_locked(&path);
+            assert_eq!(get_lock_state(&path).unwrap(), LockState::Owned(owner));
+        }
+
+        // Unreadable, so it can't be told whether the owner is alive
+        fs::write(&path, "pid\n").unwrap();
+        assert_eq!(get_lock_state(&path).unwrap(), LockState::Unknown);
+        assert_locked(&path);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "pid\n");
+    }
+
+    #[test]
+    fn breaks_only_the_expected_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("feedutils.tsv.lock");
+        assert!(!break_lock(&path, &LockState::Unlocked).unwrap());
+
+        fs::write(&path, "garbage").unwrap();
+        let stale = LockState::Owned(owner(dead_pid(), &get_hostname()));
+        assert!(!break_lock(&path, &stale).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "garbage");
+
+        assert!(break_lock(&path, &LockState::Unknown).unwrap());
+        assert_eq!(get_lock_state(&path).unwrap(), LockState::Unlocked);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
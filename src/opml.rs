@@ -0,0 +1,421 @@
+// OPML import and export of subscriptions.
+// Feeds are described by their directory: the feed URL is read from a `url`
+// file or recovered from a curl command in the `fetch` script, the title from
+// a `title` file, and tags from a `tags` file (one per line), with the
+// `daily` file counting as the "daily" tag.
+// OPML folders are mapped onto tags naming the folder path, eg "news/tech";
+// on export the first tag is used as the folder.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+use url::Url;
+use xml::reader::EventReader;
+use xml::writer::{EmitterConfig, XmlEvent};
+
+use crate::{get_feed_config_dir, get_feed_dir, Config, FeedDirError};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Subscription {
+    pub name: String,
+    pub title: Option<String>,
+    pub url: Option<String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum OpmlError {
+    #[error(transparent)]
+    FeedDirError(#[from] FeedDirError),
+    #[error("{source}: {path}")]
+    IoError {
+        source: io::Error,
+        path: PathBuf,
+    },
+    #[error("Feed already exists: {name}")]
+    FeedExists {
+        name: String,
+    },
+    #[error("Invalid OPML: {source}")]
+    ReadError {
+        source: xml::reader::Error,
+    },
+    #[error("Failed to write OPML: {source}")]
+    WriteError {
+        source: xml::writer::Error,
+    },
+}
+
+fn read_optional_file(path: &Path) -> Result<Option<String>, OpmlError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(OpmlError::IoError{ source: e, path: path.to_path_buf() }),
+    }
+}
+
+fn url_from_fetch_script(script: &str) -> Option<String> {
+    // Find the URL passed to curl, as in the scripts written by
+    // feed-addatom.sh and feed-addrss.sh.
+    for line in script.lines().filter(|line| line.contains("curl")) {
+        for word in line.split_whitespace() {
+            let word = word.trim_matches(|c| c == '\'' || c == '"');
+            if let Ok(url) = Url::parse(word) {
+                if url.scheme() == "http" || url.scheme() == "https" {
+                    return Some(word.to_string());
+                }
+            }
+        }
+    }
+    return None;
+}
+
+pub fn read_subscription(config: &Config, feed_name: &str) -> Result<Subscription, OpmlError> {
+    let feed_dir = get_feed_dir(config, feed_name.to_string())?;
+
+    let mut url = read_optional_file(&feed_dir.join("url"))?
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty());
+    if url.is_none() {
+        url = read_optional_file(&feed_dir.join("fetch"))?
+            .and_then(|script| url_from_fetch_script(&script));
+    }
+    let title = read_optional_file(&feed_dir.join("title"))?
+        .map(|title| title.trim().to_string())
+        .filter(|title| !title.is_empty());
+
+    let mut tags: Vec<String> = Vec::new();
+    for tag in read_optional_file(&feed_dir.join("tags"))?.unwrap_or_default().lines() {
+        let tag = tag.trim();
+        if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    if feed_dir.join("daily").exists() && !tags.iter().any(|tag| tag == "daily") {
+        tags.push("daily".to_string());
+    }
+
+    return Ok(Subscription { name: feed_name.to_string(), title, url, tags });
+}
+
+fn folder_path(subscription: &Subscription) -> Vec<String> {
+    return match subscription.tags.first() {
+        Some(tag) => tag.split('/').filter(|folder| !folder.is_empty()).map(|folder| folder.to_string()).collect(),
+        None => Vec::new(),
+    };
+}
+
+pub fn write_opml<W: Write>(writer: W, subscriptions: &[Subscription]) -> Result<(), OpmlError> {
+    // Write OPML 2.0, nesting each subscription in the folders named by its
+    // first tag and listing every tag as a category.
+    // Subscriptions without a URL can't be represented, so are skipped.
+
+    let mut subscriptions: Vec<(Vec<String>, &Subscription)> = subscriptions.iter()
+        .filter(|subscription| subscription.url.is_some())
+        .map(|subscription| (folder_path(subscription), subscription))
+        .collect();
+    subscriptions.sort_by(|a, b| (&a.0, &a.1.name).cmp(&(&b.0, &b.1.name)));
+
+    let mut writer = EmitterConfig::new().perform_indent(true).create_writer(writer);
+    let mut write = |event: XmlEvent| -> Result<(), OpmlError> {
+        return writer.write(event).map_err(|e| OpmlError::WriteError{ source: e });
+    };
+    let date_created = chrono::offset::Utc::now().to_rfc2822();
+
+    write(XmlEvent::start_element("opml").attr("version", "2.0").into())?;
+    write(XmlEvent::start_element("head").into())?;
+    write(XmlEvent::start_element("title").into())?;
+    write(XmlEvent::characters("feedutils subscriptions"))?;
+    write(XmlEvent::end_element().into())?;
+    write(XmlEvent::start_element("dateCreated").into())?;
+    write(XmlEvent::characters(&date_created))?;
+    write(XmlEvent::end_element().into())?;
+    write(XmlEvent::end_element().into())?;
+    write(XmlEvent::start_element("body").into())?;
+
+    let mut open_folders: Vec<String> = Vec::new();
+    for (folders, subscription) in subscriptions.iter() {
+        // Close the folders this subscription isn't in, then open the rest
+        let common = open_folders.iter().zip(folders.iter()).take_while(|(a, b)| a == b).count();
+        while open_folders.len() > common {
+            write(XmlEvent::end_element().into())?;
+            open_folders.pop();
+        }
+        for folder in folders.iter().skip(common) {
+            write(XmlEvent::start_element("outline").attr("text", folder).into())?;
+            open_folders.push(folder.clone());
+        }
+
+        let text = subscription.title.as_deref().unwrap_or(&subscription.name);
+        let categories = subscription.tags.iter().map(|tag| format!("/{}", tag)).collect::<Vec<String>>().join(",");
+        let mut outline = XmlEvent::start_element("outline")
+            .attr("type", "rss")
+            .attr("text", text)
+            .attr("title", text)
+            .attr("xmlUrl", subscription.url.as_deref().unwrap_or_default());
+        if !categories.is_empty() {
+            outline = outline.attr("category", &categories);
+        }
+        write(outline.into())?;
+        write(XmlEvent::end_element().into())?;
+    }
+    for _ in open_folders {
+        write(XmlEvent::end_element().into())?;
+    }
+
+    write(XmlEvent::end_element().into())?;
+    write(XmlEvent::end_element().into())?;
+    return Ok(());
+}
+
+fn feed_name_from_title(title: &str) -> String {
+    // A name usable as a directory, keeping it readable.
+    let mut name = String::new();
+    for c in title.trim().chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+            name.push(c);
+        } else if !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    return name.trim_matches(|c| c == '-' || c == '.').to_string();
+}
+
+pub fn parse_opml<R: Read>(reader: R) -> Result<Vec<Subscription>, OpmlError> {
+    // Read the subscriptions from OPML. Feeds are named after their title,
+    // falling back to the host name of the feed URL.
+
+    let mut subscriptions = Vec::new();
+    // Folder names enclosing the current outline; None for feed outlines
+    let mut folders: Vec<Option<String>> = Vec::new();
+
+    for event in EventReader::new(reader) {
+        match event.map_err(|e| OpmlError::ReadError{ source: e })? {
+            xml::reader::XmlEvent::StartElement { name, attributes, .. } if name.local_name == "outline" => {
+                let attributes: HashMap<String, String> = attributes.into_iter()
+                    .map(|attribute| (attribute.name.local_name, attribute.value))
+                    .collect();
+                let text = attributes.get("title").or(attributes.get("text")).map(|text| text.trim().to_string());
+
+                let url = match attributes.get("xmlUrl") {
+                    Some(url) => url.trim().to_string(),
+                    None => {
+                        folders.push(Some(text.unwrap_or_default()));
+                        continue;
+                    },
+                };
+                folders.push(None);
+
+                let mut tags: Vec<String> = Vec::new();
+                let path: Vec<&str> = folders.iter().flatten().map(|folder| folder.as_str()).filter(|folder| !folder.is_empty()).collect();
+                if !path.is_empty() {
+                    tags.push(path.join("/"));
+                }
+                for category in attributes.get("category").map(|categories| categories.split(',')).into_iter().flatten() {
+                    let category = category.trim().trim_matches('/');
+                    if !category.is_empty() && !tags.iter().any(|tag| tag == category) {
+                        tags.push(category.to_string());
+                    }
+                }
+
+                let host = Url::parse(&url).ok().and_then(|url| url.host_str().map(|host| host.to_string()));
+                let name = feed_name_from_title(text.as_deref().or(host.as_deref()).unwrap_or_default());
+                subscriptions.push(Subscription {
+                    name,
+                    title: text.filter(|text| !text.is_empty()),
+                    url: Some(url),
+                    tags,
+                });
+            },
+            xml::reader::XmlEvent::EndElement { name } if name.local_name == "outline" => {
+                folders.pop();
+            },
+            _ => {},
+        }
+    }
+    return Ok(subscriptions);
+}
+
+pub fn name_with_host(subscription: &Subscription) -> Option<String> {
+    // Another name for a subscription whose name is taken by a different
+    // feed, telling them apart by the host of the feed URL. None if the name
+    // already comes from the host.
+    let url = Url::parse(subscription.url.as_deref()?).ok()?;
+    let host = feed_name_from_title(url.host_str()?);
+    if subscription.name == host {
+        return None;
+    }
+    return Some(format!("{}-{}", subscription.name, host));
+}
+
+pub fn add_subscription(config: &Config, subscription: &Subscription) -> Result<PathBuf, OpmlError> {
+    // Create a feed directory for the subscription, fetched from its url
+    // file as feed-addatom.sh does. Existing feeds are never overwritten.
+
+    let config_dir = get_feed_config_dir(config).ok_or(FeedDirError::CannotReadConfigDir)?;
+    let feed_dir = config_dir.join(&subscription.name);
+    let io_error = |e, path: &Path| OpmlError::IoError{ source: e, path: path.to_path_buf() };
+
+    match fs::create_dir(&feed_dir) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(OpmlError::FeedExists{ name: subscription.name.clone() }),
+        Err(e) => return Err(io_error(e, &feed_dir)),
+    }
+
+    let open_path = feed_dir.join("open");
+    symlink("../open", &open_path).map_err(|e| io_error(e, &open_path))?;
+
+    let url = subscription.url.clone().unwrap_or_default();
+    let url_path = feed_dir.join("url");
+    fs::write(&url_path, url + "\n").map_err(|e| io_error(e, &url_path))?;
+    if let Some(title) = &subscription.title {
+        let title_path = feed_dir.join("title");
+        fs::write(&title_path, title.clone() + "\n").map_err(|e| io_error(e, &title_path))?;
+    }
+    let tags: Vec<&String> = subscription.tags.iter().filter(|tag| *tag != "daily").collect();
+    if !tags.is_empty() {
+        let tags_path = feed_dir.join("tags");
+        let contents: String = tags.iter().map(|tag| format!("{}\n", tag)).collect();
+        fs::write(&tags_path, contents).map_err(|e| io_error(e, &tags_path))?;
+    }
+    if subscription.tags.len() > tags.len() {
+        let daily_path = feed_dir.join("daily");
+        fs::write(&daily_path, "").map_err(|e| io_error(e, &daily_path))?;
+    }
+
+    return Ok(feed_dir);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(name: &str, title: Option<&str>, url: &str, tags: &[&str]) -> Subscription {
+        return Subscription {
+            name: name.to_string(),
+            title: title.map(|title| title.to_string()),
+            url: Some(url.to_string()),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+    }
+
+    fn round_trip(subscriptions: &[Subscription]) -> Vec<Subscription> {
+        let mut opml = Vec::new();
+        write_opml(&mut opml, subscriptions).unwrap();
+        return parse_opml(opml.as_slice()).unwrap();
+    }
+
+    #[test]
+    fn round_trips_subscriptions() {
+        // Written out by folder, then by name
+        let subscriptions = vec![
+            subscription("Plain", Some("Plain"), "https://example.org/feed", &[]),
+            subscription("World", Some("World"), "https://news.example.org/world.xml", &["news"]),
+            subscription("Tech-News", Some("Tech News"), "https://news.example.org/tech.xml", &["news/tech", "daily"]),
+        ];
+        assert_eq!(round_trip(&subscriptions), subscriptions);
+
+        // Without a URL there is nothing to subscribe to
+        let mut no_url = subscription("Nowhere", Some("Nowhere"), "", &[]);
+        no_url.url = None;
+        assert_eq!(round_trip(&[no_url]), []);
+    }
+
+    #[test]
+    fn escapes_attributes() {
+        let subscriptions = vec![
+            subscription("Tom-Jerry-s-News", Some("Tom & Jerry's \"<News>\""), "https://example.org/feed?a=1&b=2", &["a&b"]),
+        ];
+        assert_eq!(round_trip(&subscriptions), subscriptions);
+
+        let opml = r#"<opml version="1.0"><body>
+            <outline text="Q&amp;A &lt;daily&gt;" xmlUrl="https://example.org/?x=1&amp;y=&quot;2&quot;"/>
+        </body></opml>"#;
+        let parsed = parse_opml(opml.as_bytes()).unwrap();
+        assert_eq!(parsed, [subscription("Q-A-daily", Some("Q&A <daily>"), "https://example.org/?x=1&y=\"2\"", &[])]);
+    }
+
+    #[test]
+    fn reads_nested_outlines() {
+        let opml = r#"<?xml version="1.0"?>
+            <opml version="2.0"><head><title>Feeds</title></head><body>
+              <outline text="News">
+                <outline title="Tech" text="ignored">
+                  <outline text="Gadgets" xmlUrl="https://gadgets.example.org/rss" category="/reviews,/News/Tech"/>
+                </outline>
+                <outline text="World" xmlUrl="https://world.example.org/rss"/>
+              </outline>
+              <outline text="Top" xmlUrl="https://top.example.org/rss"/>
+            </body></opml>"#;
+        assert_eq!(parse_opml(opml.as_bytes()).unwrap(), [
+            subscription("Gadgets", Some("Gadgets"), "https://gadgets.example.org/rss", &["News/Tech", "reviews"]),
+            subscription("World", Some("World"), "https://world.example.org/rss", &["News"]),
+            subscription("Top", Some("Top"), "https://top.example.org/rss", &[]),
+        ]);
+    }
+
+    #[test]
+    fn skips_outlines_without_feeds() {
+        // Outlines without an xmlUrl are folders, so on their own add
+        // nothing; feeds without a title are named after their host
+        let opml = r#"<opml version="2.0"><body>
+              <outline text="Empty folder"/>
+              <outline text="Bookmark" htmlUrl="https://example.org/"/>
+              <outline xmlUrl="https://untitled.example.org/feed"/>
+            </body></opml>"#;
+        assert_eq!(parse_opml(opml.as_bytes()).unwrap(), [
+            subscription("untitled.example.org", None, "https://untitled.example.org/feed", &[]),
+        ]);
+
+        assert!(matches!(parse_opml("<opml><body>".as_bytes()), Err(OpmlError::ReadError{ .. })));
+    }
+
+    #[test]
+    fn finds_urls_in_fetch_scripts() {
+        // As written by feed-addatom.sh and feed-addrss.sh before they wrote
+        // url files
+        let script = "#!/usr/bin/env sh\nexec curl -L -o - 'https://example.org/atom.xml'\n";
+        assert_eq!(url_from_fetch_script(script).as_deref(), Some("https://example.org/atom.xml"));
+        let script = "#!/usr/bin/env sh\nexec curl -L -o - \"http://example.org/rss?id=1\"\n";
+        assert_eq!(url_from_fetch_script(script).as_deref(), Some("http://example.org/rss?id=1"));
+
+        assert_eq!(url_from_fetch_script("#!/bin/sh\nwget -O - https://example.org/feed\n"), None);
+        assert_eq!(url_from_fetch_script("#!/bin/sh\ncurl -s ftp://example.org/feed\n"), None);
+    }
+
+    #[test]
+    fn reads_subscriptions_from_either_script_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { feed_dir: Some(dir.path().to_path_buf()), ..Config::default() };
+
+        // feed-addatom.sh and feed-addrss.sh now write a url file
+        let feed_dir = dir.path().join("atom");
+        fs::create_dir(&feed_dir).unwrap();
+        fs::write(feed_dir.join("url"), "https://example.org/atom.xml\n").unwrap();
+        fs::write(feed_dir.join("tags"), "news\n\nnews\n").unwrap();
+        fs::write(feed_dir.join("daily"), "").unwrap();
+        assert_eq!(read_subscription(&config, "atom").unwrap(),
+                   subscription("atom", None, "https://example.org/atom.xml", &["news", "daily"]));
+
+        // Older feeds only have the fetch script
+        let feed_dir = dir.path().join("rss");
+        fs::create_dir(&feed_dir).unwrap();
+        fs::write(feed_dir.join("fetch"), "#!/usr/bin/env sh\nexec curl -L -o - 'https://example.org/rss.xml'\n").unwrap();
+        fs::write(feed_dir.join("title"), "Some RSS\n").unwrap();
+        assert_eq!(read_subscription(&config, "rss").unwrap(),
+                   subscription("rss", Some("Some RSS"), "https://example.org/rss.xml", &[]));
+
+        // Which add_subscription writes back out
+        let added = add_subscription(&config, &subscription("copy", Some("Some RSS"), "https://example.org/rss.xml", &["daily"])).unwrap();
+        assert_eq!(added, dir.path().join("copy"));
+        assert_eq!(read_subscription(&config, "copy").unwrap(),
+                   subscription("copy", Some("Some RSS"), "https://example.org/rss.xml", &["daily"]));
+        assert!(matches!(add_subscription(&config, &subscription("rss", None, "https://example.org/", &[])),
+                         Err(OpmlError::FeedExists{ .. })));
+    }
+}
@@ -0,0 +1,168 @@
+// Updating several feeds at once.
+// Fetching is by far the slowest part of an update, so the fetch executables
+// run in worker threads, up to a limit overall and optionally per host.
+// Merging into the database still happens one feed at a time, on the calling
+// thread, as each fetch finishes.
+
+use std::collections::{HashMap, VecDeque};
+use std::panic;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use crate::{fetch_feed, get_feed_host, merge_fetched_feed, record_update, Config, FetchedFeed, UpdateError, UpdateOptions};
+
+type FetchResult = (String, Option<String>, Result<FetchedFeed, UpdateError>);
+
+pub fn update_feeds<F>(config: &Config, feed_names: Vec<String>, options: &UpdateOptions, report: F)
+    where F: FnMut(&str, Result<(), UpdateError>)
+{
+    // Update the feeds, calling report with the result for each feed as it
+    // finishes. Feeds are started in the order given.
+    update_feeds_with(config, feed_names, options, fetch_feed, report);
+}
+
+fn update_feeds_with<G, F>(config: &Config, feed_names: Vec<String>, options: &UpdateOptions, fetch: G, mut report: F)
+    where G: Fn(&Config, &str) -> Result<FetchedFeed, UpdateError> + Send + Sync + 'static,
+          F: FnMut(&str, Result<(), UpdateError>)
+{
+    // As update_feeds, fetching each feed with fetch.
+
+    let jobs = options.jobs.max(1);
+    let mut pending: VecDeque<(String, Option<String>)> = feed_names.into_iter()
+        .map(|feed_name| {
+            let host = options.per_host.and_then(|_| get_feed_host(config, &feed_name));
+            return (feed_name, host);
+        })
+        .collect();
+    let mut running = 0;
+    let mut running_per_host: HashMap<String, usize> = HashMap::new();
+    let (sender, receiver) = mpsc::channel::<FetchResult>();
+    // Shared with the workers
+    let worker_config = Arc::new(config.clone());
+    let fetch = Arc::new(fetch);
+
+    loop {
+        // Start as many fetches as the limits allow
+        while running < jobs {
+            let startable = pending.iter().position(|(_, host)| match (host, options.per_host) {
+                (Some(host), Some(limit)) => running_per_host.get(host).copied().unwrap_or(0) < limit,
+                _ => true,
+            });
+            let (feed_name, host) = match startable.and_then(|position| pending.remove(position)) {
+                Some(feed) => feed,
+                None => break,
+            };
+            if let Some(host) = &host {
+                *running_per_host.entry(host.clone()).or_default() += 1;
+            }
+            running += 1;
+
+            // A worker must always send a result, or this would wait for it
+            // forever, so a panic is reported as a failed fetch
+            let sender = sender.clone();
+            let worker_config = Arc::clone(&worker_config);
+            let fetch = Arc::clone(&fetch);
+            thread::spawn(move || {
+                let fetch = panic::AssertUnwindSafe(|| fetch(&worker_config, &feed_name));
+                let result = panic::catch_unwind(fetch).unwrap_or_else(|payload| {
+                    let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    return Err(UpdateError::FetchPanicked{ message });
+                });
+                let _ = sender.send((feed_name, host, result));
+            });
+        }
+        if running == 0 {
+            break;
+        }
+
+        // Every worker sends its result, so this waits for the next fetch to
+        // finish
+        let (feed_name, host, result) = match receiver.recv() {
+            Ok(fetched) => fetched,
+            Err(_) => break,
+        };
+        running -= 1;
+        if let Some(host) = host {
+            if let Some(count) = running_per_host.get_mut(&host) {
+                *count -= 1;
+            }
+        }
+
+        let result = result.and_then(|fetched| merge_fetched_feed(config, &feed_name, fetched));
+        let _ = record_update(config, &feed_name, &result);
+        report(&feed_name, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::Mutex;
+    use std::time;
+
+    #[test]
+    fn limits_fetches_per_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { feed_dir: Some(dir.path().to_path_buf()), ..Config::default() };
+        let mut feed_names = Vec::new();
+        for (feed_name, host) in [("a1", "a.example"), ("a2", "a.example"), ("a3", "a.example"), ("b1", "b.example"), ("b2", "b.example")] {
+            fs::create_dir(dir.path().join(feed_name)).unwrap();
+            fs::write(dir.path().join(feed_name).join("url"), format!("https://{}/{}.xml\n", host, feed_name)).unwrap();
+            feed_names.push(feed_name.to_string());
+        }
+        let options = UpdateOptions { jobs: 4, per_host: Some(2), ..UpdateOptions::default() };
+
+        // Fetches running per host, and the most seen at once
+        let running: Arc<Mutex<HashMap<String, (usize, usize)>>> = Arc::default();
+        let fetch_running = Arc::clone(&running);
+        let fetch = move |config: &Config, feed_name: &str| -> Result<FetchedFeed, UpdateError> {
+            let host = get_feed_host(config, feed_name).unwrap();
+            {
+                let mut running = fetch_running.lock().unwrap();
+                let (now, most) = running.entry(host.clone()).or_default();
+                *now += 1;
+                *most = (*most).max(*now);
+            }
+            thread::sleep(time::Duration::from_millis(50));
+            fetch_running.lock().unwrap().get_mut(&host).unwrap().0 -= 1;
+            return Ok(FetchedFeed::NotModified);
+        };
+
+        let mut reported = Vec::new();
+        update_feeds_with(&config, feed_names, &options, fetch, |feed_name, result| {
+            assert!(result.is_ok());
+            reported.push(feed_name.to_string());
+        });
+        reported.sort();
+        assert_eq!(reported, ["a1", "a2", "a3", "b1", "b2"]);
+        let running = running.lock().unwrap();
+        assert_eq!(running["a.example"], (0, 2));
+        assert_eq!(running["b.example"], (0, 2));
+    }
+
+    #[test]
+    fn reports_panicking_fetches() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config { feed_dir: Some(dir.path().to_path_buf()), ..Config::default() };
+        let feed_names = vec!["ok".to_string(), "panics".to_string()];
+        let fetch = |_: &Config, feed_name: &str| -> Result<FetchedFeed, UpdateError> {
+            if feed_name == "panics" {
+                panic!("fetch of {} failed", feed_name);
+            }
+            return Ok(FetchedFeed::NotModified);
+        };
+
+        let mut reported = HashMap::new();
+        update_feeds_with(&config, feed_names, &UpdateOptions::default(), fetch, |feed_name, result| {
+            reported.insert(feed_name.to_string(), result);
+        });
+        assert!(matches!(reported["ok"], Ok(())));
+        match &reported["panics"] {
+            Err(UpdateError::FetchPanicked{ message }) => assert_eq!(message, "fetch of panics failed"),
+            _ => panic!("panic not reported"),
+        }
+    }
+}
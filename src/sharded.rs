@@ -0,0 +1,416 @@
+// Sharded database backend.
+// Each feed's entries live in their own TSV database inside a directory, so
+// that feeds can be updated and read independently, each under its own lock.
+// Modifications across all feeds lock every shard they will write, in name
+// order, before writing any of them, and remove shards left empty.
+// As each shard has its own backups, the shards backed up by each
+// modification are logged, so that undoing the last few modifications takes
+// each shard back only as far as they changed it.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::{escape_field, get_backup_path, get_sibling_path, lock, lock_tsv_database, read_seen_ids,
+            read_tsv_backup, read_tsv_contents, set_tsv_read_state, unescape_field, write_tsv_contents, DatabaseReadError, Entry,
+            EntryStore, ModifyDatabaseError, SeenIds, SeenModifier, StoreError, StoreOptions, TsvContents,
+            TsvStore};
+
+// Held by modifications across all feeds
+const ALL_LOCK: &str = "all.lock";
+// One line per modification, of the tab separated (and escaped) feeds it
+// backed up
+const OPERATIONS: &str = "operations";
+const OPERATIONS_LOCK: &str = "operations.lock";
+
+fn write_error(source: io::Error, path: PathBuf, operation: &str) -> StoreError {
+    return StoreError::ModifyDatabaseError(ModifyDatabaseError::WriteError{ source, path, operation: operation.to_string() });
+}
+
+pub struct ShardedStore {
+    dir: PathBuf,
+    options: StoreOptions,
+}
+
+impl ShardedStore {
+    pub fn new(dir: PathBuf) -> ShardedStore {
+        return ShardedStore::with_options(dir, StoreOptions::default());
+    }
+
+    pub fn with_options(dir: PathBuf, options: StoreOptions) -> ShardedStore {
+        return ShardedStore { dir, options };
+    }
+
+    fn shard_path(&self, feed_name: &str) -> PathBuf {
+        return self.dir.join(format!("{}.tsv", feed_name));
+    }
+
+    fn list_dir(&self, suffix: &str) -> Result<Vec<(String, PathBuf)>, StoreError> {
+        // Files in the directory with the given suffix, by name, along with
+        // the name less the suffix.
+        let read_error = |e| StoreError::ReadError{ source: DatabaseReadError::IoError{ source: e, path: self.dir.clone() } };
+
+        let mut files = Vec::new();
+        for dir_entry in fs::read_dir(&self.dir).map_err(read_error)? {
+            let file_name = dir_entry.map_err(read_error)?.file_name();
+            if let Some(name) = file_name.to_str().and_then(|name| name.strip_suffix(suffix)) {
+                files.push((name.to_string(), self.dir.join(&file_name)));
+            }
+        }
+        files.sort();
+        return Ok(files);
+    }
+
+    fn shards(&self) -> Result<Vec<(String, PathBuf)>, StoreError> {
+        // Feed names and shard paths, in name order.
+        return self.list_dir(".tsv");
+    }
+
+    fn shard_store(&self, feed_name: &str) -> TsvStore {
+        return TsvStore::with_options(self.shard_path(feed_name), self.options);
+    }
+
+    fn lock(&self, name: &str) -> Result<lock::LockGuard, StoreError> {
+        let lockfile_path = self.dir.join(name);
+        return lock::acquire(&lockfile_path, &self.options.lock)
+            .map_err(|e| StoreError::ModifyDatabaseError(ModifyDatabaseError::LockCreateError{ source: e, path: lockfile_path }));
+    }
+
+    fn read_operations(&self) -> Result<Vec<Vec<String>>, StoreError> {
+        // The feeds backed up by each logged modification, oldest first.
+        let path = self.dir.join(OPERATIONS);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(StoreError::ReadError{ source: DatabaseReadError::IoError{ source: e, path } }),
+        };
+        return Ok(contents.lines().map(|line| line.split('\t').map(unescape_field).collect()).collect());
+    }
+
+    fn log_operation(&self, feed_names: &[String]) -> Result<(), StoreError> {
+        // Record a modification which backed up the given shards, keeping as
+        // many as there are backups. The backups of shards which have since
+        // been removed are deleted once nothing in the log needs them.
+
+        if feed_names.is_empty() || self.options.backups == 0 {
+            return Ok(());
+        }
+        let _lock = self.lock(OPERATIONS_LOCK)?;
+        let mut operations = self.read_operations()?;
+        operations.push(feed_names.to_vec());
+        let dropped = operations.len().saturating_sub(self.options.backups);
+        let dropped_operations: Vec<Vec<String>> = operations.drain(..dropped).collect();
+
+        let path = self.dir.join(OPERATIONS);
+        let new_path = get_sibling_path(&path, ".new");
+        let contents: String = operations.iter()
+            .map(|feed_names| feed_names.iter().map(|feed_name| escape_field(feed_name)).collect::<Vec<String>>().join("\t") + "\n")
+            .collect();
+        fs::write(&new_path, contents).and_then(|_| fs::rename(&new_path, &path))
+            .map_err(|e| write_error(e, path, "write"))?;
+
+        for feed_name in dropped_operations.into_iter().flatten() {
+            let shard_path = self.shard_path(&feed_name);
+            if shard_path.exists() || operations.iter().flatten().any(|logged| *logged == feed_name) {
+                continue;
+            }
+            for number in 1..=self.options.backups {
+                let _ = fs::remove_file(get_backup_path(&shard_path, number));
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_shard(&self, feed_name: &str, contents: &TsvContents, modified_entries: &Vec<Entry>, seen: Option<SeenIds>) -> Result<(), StoreError> {
+        // Replace a locked shard, removing it (though not its backups) if
+        // nothing is left in it.
+
+        let path = self.shard_path(feed_name);
+        write_tsv_contents(&path, &self.options, contents, modified_entries, seen)?;
+        if !modified_entries.is_empty() {
+            return Ok(());
+        }
+        let seen_path = get_sibling_path(&path, ".seen");
+        if !read_seen_ids(&seen_path).map_err(|e| StoreError::ReadError{ source: e })?.ids.is_empty() {
+            return Ok(());
+        }
+        for path in [seen_path, path] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(write_error(e, path, "remove")),
+                _ => {},
+            }
+        }
+        return Ok(());
+    }
+
+    fn modify_all<F>(&self, modifier: F, track_seen: bool) -> Result<(), StoreError>
+        where F: FnOnce(Vec<Entry>, &mut SeenIds) -> Vec<Entry>
+    {
+        // Only one modification across all feeds at a time, as the shards
+        // to lock aren't all known until the modifier has run
+        let _all_lock = self.lock(ALL_LOCK)?;
+        let shards = self.shards()?;
+        let mut locks = Vec::new();
+        for (_, path) in shards.iter() {
+            locks.push(lock_tsv_database(path, &self.options)?);
+        }
+
+        let mut contents = BTreeMap::new();
+        let mut entries = Vec::new();
+        let mut seen = SeenIds::default();
+        for (feed_name, path) in shards.iter() {
+            let shard_contents = read_tsv_contents(path, false, track_seen)?;
+            entries.extend(shard_contents.entries.iter().cloned());
+            if let Some(shard_seen) = &shard_contents.seen {
+                seen.ids.extend(shard_seen.ids.clone());
+            }
+            contents.insert(feed_name.clone(), shard_contents);
+        }
+
+        let modified_entries = modifier(entries, &mut seen);
+
+        let mut feed_entries: HashMap<String, Vec<Entry>> = HashMap::new();
+        for entry in modified_entries {
+            feed_entries.entry(entry.feed.clone()).or_default().push(entry);
+        }
+
+        // Feeds which didn't have a shard yet are locked too before anything
+        // is written. One may have been created in the meantime, so add to
+        // rather than replace anything already there.
+        let mut new_feeds: BTreeSet<String> = feed_entries.keys().cloned().collect();
+        if track_seen {
+            new_feeds.extend(seen.ids.keys().map(|(feed, _)| feed.clone()));
+        }
+        new_feeds.retain(|feed_name| !contents.contains_key(feed_name));
+        for feed_name in new_feeds {
+            let path = self.shard_path(&feed_name);
+            locks.push(lock_tsv_database(&path, &self.options)?);
+            let shard_contents = read_tsv_contents(&path, true, track_seen)?;
+            let new_entries = feed_entries.remove(&feed_name).unwrap_or_default();
+            let mut merged_entries: Vec<Entry> = shard_contents.entries.iter()
+                .filter(|entry| !new_entries.iter().any(|new_entry| new_entry.id == entry.id))
+                .cloned()
+                .collect();
+            merged_entries.extend(new_entries);
+            feed_entries.insert(feed_name.clone(), merged_entries);
+            if let Some(shard_seen) = &shard_contents.seen {
+                for (key, last_seen) in shard_seen.ids.iter() {
+                    seen.ids.entry(key.clone()).or_insert(*last_seen);
+                }
+            }
+            contents.insert(feed_name, shard_contents);
+        }
+
+        // Only rewrite the shards which have changed
+        let feed_seen = |feed_name: &str| -> Option<SeenIds> {
+            if !track_seen {
+                return None;
+            }
+            let mut shard_seen = seen.clone();
+            shard_seen.ids.retain(|(feed, _), _| feed == feed_name);
+            return Some(shard_seen);
+        };
+        let mut backed_up = Vec::new();
+        for (feed_name, shard_contents) in contents.iter() {
+            let modified_feed_entries = feed_entries.remove(feed_name).unwrap_or_default();
+            let modified_seen = feed_seen(feed_name);
+            if modified_feed_entries == shard_contents.entries && modified_seen == shard_contents.seen {
+                continue;
+            }
+            if modified_feed_entries != shard_contents.entries {
+                backed_up.push(feed_name.clone());
+            }
+            self.write_shard(feed_name, shard_contents, &modified_feed_entries, modified_seen)?;
+        }
+        self.log_operation(&backed_up)?;
+
+        return Ok(());
+    }
+}
+
+impl EntryStore for ShardedStore {
+    fn entries(&self) -> Result<Vec<Entry>, StoreError> {
+        // Each shard is consistent, but as no locks are taken the shards may
+        // be from slightly different points in time.
+        let mut entries = Vec::new();
+        for (feed_name, _) in self.shards()? {
+            entries.extend(self.shard_store(&feed_name).entries()?);
+        }
+        return Ok(entries);
+    }
+
+    fn feed_entries(&self, feed_name: &str) -> Result<Vec<Entry>, StoreError> {
+        if !self.shard_path(feed_name).exists() {
+            return Ok(Vec::new());
+        }
+        return self.shard_store(feed_name).entries();
+    }
+
+    fn modify(&self, modifier: Box<dyn FnOnce(Vec<Entry>) -> Vec<Entry> + '_>) -> Result<(), StoreError> {
+        return self.modify_all(|entries, _| modifier(entries), false);
+    }
+
+    fn modify_seen(&self, modifier: SeenModifier<'_>) -> Result<(), StoreError> {
+        return self.modify_all(modifier, true);
+    }
+
+    fn modify_feed(&self, feed_name: &str, modifier: SeenModifier<'_>) -> Result<(), StoreError> {
+        let path = self.shard_path(feed_name);
+        let _lock = lock_tsv_database(&path, &self.options)?;
+        let contents = read_tsv_contents(&path, true, true)?;
+        let mut seen = contents.seen.clone().unwrap_or_default();
+        let modified_entries = modifier(contents.entries.clone(), &mut seen);
+        self.write_shard(feed_name, &contents, &modified_entries, Some(seen))?;
+        if modified_entries != contents.entries {
+            self.log_operation(&[feed_name.to_string()])?;
+        }
+        return Ok(());
+    }
+
+    fn seen_ids(&self) -> Result<SeenIds, StoreError> {
+        let mut seen = SeenIds::default();
+        for (_, path) in self.shards()? {
+            let shard_seen = read_seen_ids(&get_sibling_path(&path, ".seen"))
+                .map_err(|e| StoreError::ReadError{ source: e })?;
+            seen.ids.extend(shard_seen.ids);
+        }
+        return Ok(seen);
+    }
+
+    fn read_backup(&self, number: usize) -> Result<Vec<Entry>, StoreError> {
+        // Each feed as it was before the last few logged modifications, from
+        // the shard's backup for however many of them changed it. Feeds they
+        // didn't change are left as they are.
+
+        let operations = self.read_operations()?;
+        if number > operations.len() {
+            let source = io::Error::from(io::ErrorKind::NotFound);
+            let path = self.dir.join(format!("*.tsv.backup.{}", number));
+            return Err(StoreError::ReadError{ source: DatabaseReadError::IoError{ source, path } });
+        }
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for feed_name in operations[operations.len() - number..].iter().flatten() {
+            *counts.entry(feed_name.clone()).or_default() += 1;
+        }
+
+        let mut feed_names: BTreeSet<String> = self.shards()?.into_iter().map(|(feed_name, _)| feed_name).collect();
+        feed_names.extend(counts.keys().cloned());
+        let mut entries = Vec::new();
+        for feed_name in feed_names {
+            match counts.get(&feed_name) {
+                Some(count) => entries.extend(read_tsv_backup(&self.shard_path(&feed_name), *count)
+                    .map_err(|e| StoreError::ReadError{ source: e })?),
+                None => entries.extend(self.feed_entries(&feed_name)?),
+            }
+        }
+        return Ok(entries);
+    }
+
+    fn initialize(&self) -> Result<(), StoreError> {
+        return fs::create_dir_all(&self.dir).map_err(|e| write_error(e, self.dir.clone(), "create"));
+    }
+
+    fn lockfiles(&self) -> Vec<PathBuf> {
+        // Including locks on shards which are still being created
+        let mut lockfiles = vec![self.dir.join(ALL_LOCK), self.dir.join(OPERATIONS_LOCK)];
+        lockfiles.extend(self.list_dir(".tsv.lock")
+            .map(|lockfiles| lockfiles.into_iter().map(|(_, path)| path).collect::<Vec<_>>())
+            .unwrap_or_default());
+        return lockfiles;
+    }
+
+    fn set_read(&self, feed_name: &str, entry_id: &str, read: bool) -> Result<(), StoreError> {
+        return Ok(set_tsv_read_state(self.shard_path(feed_name), &self.options, feed_name, entry_id, read)?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LockOptions, LockWait};
+
+    fn entry(feed: &str, id: &str, title: &str) -> Entry {
+        return Entry {
+            feed: feed.to_string(),
+            id: id.to_string(),
+            updated: "2020-01-01T00:00:00Z".to_string(),
+            title: title.to_string(),
+            link: format!("http://example.org/{}", id),
+            read: false,
+            starred: false,
+            read_at: None,
+            first_seen: None,
+        };
+    }
+
+    fn titles(entries: &[Entry]) -> Vec<(String, String)> {
+        let mut titles: Vec<(String, String)> = entries.iter().map(|entry| (entry.feed.clone(), entry.title.clone())).collect();
+        titles.sort();
+        return titles;
+    }
+
+    #[test]
+    fn undoes_logged_modifications() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StoreOptions { lock: LockOptions { wait: LockWait::FailFast, ..LockOptions::default() }, ..StoreOptions::default() };
+        let store = ShardedStore::with_options(dir.path().join("feedutils.d"), options);
+        store.initialize().unwrap();
+        let tabbed = "tab\tname";
+
+        // One feed, then every feed
+        store.modify_feed(tabbed, Box::new(|mut entries, _| { entries.push(entry(tabbed, "1", "First")); entries })).unwrap();
+        store.modify(Box::new(|mut entries| {
+            entries[0].title = "Changed".to_string();
+            entries.push(entry("b", "1", "Other"));
+            entries
+        })).unwrap();
+        assert_eq!(fs::read_to_string(store.dir.join(OPERATIONS)).unwrap(), "tab\\tname\nb\ttab\\tname\n");
+        assert_eq!(store.read_operations().unwrap(), [vec![tabbed.to_string()], vec!["b".to_string(), tabbed.to_string()]]);
+
+        // Each feed goes back as far as the undone modifications changed it
+        let backup = store.read_backup(1).unwrap();
+        assert_eq!(titles(&backup), [(tabbed.to_string(), "First".to_string())]);
+        assert!(store.read_backup(2).unwrap().is_empty());
+        assert!(store.read_backup(3).is_err());
+
+        // Undoing is logged in turn
+        store.modify(Box::new(|_| backup)).unwrap();
+        assert_eq!(titles(&store.entries().unwrap()), [(tabbed.to_string(), "First".to_string())]);
+        assert!(!store.shard_path("b").exists());
+        assert_eq!(store.read_operations().unwrap().len(), 3);
+        let redo = store.read_backup(1).unwrap();
+        assert_eq!(titles(&redo), [("b".to_string(), "Other".to_string()), (tabbed.to_string(), "Changed".to_string())]);
+    }
+
+    #[test]
+    fn locks_shards_under_the_all_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StoreOptions { lock: LockOptions { wait: LockWait::FailFast, ..LockOptions::default() }, ..StoreOptions::default() };
+        let store = ShardedStore::with_options(dir.path().join("feedutils.d"), options);
+        store.initialize().unwrap();
+        let add = |feed: &'static str| -> SeenModifier<'static> {
+            return Box::new(move |mut entries, _| { entries.push(entry(feed, &entries.len().to_string(), "Title")); entries });
+        };
+        store.modify_feed("a", add("a")).unwrap();
+
+        // Modifying every feed waits for other modifications of every feed,
+        // and for each shard, but single feeds only wait for their own shard
+        let all_lock = store.lock(ALL_LOCK).unwrap();
+        assert!(store.modify(Box::new(|entries| entries)).is_err());
+        store.modify_feed("a", add("a")).unwrap();
+        drop(all_lock);
+
+        let shard_lock = lock_tsv_database(&store.shard_path("a"), &store.options).unwrap();
+        assert!(store.modify(Box::new(|entries| entries)).is_err());
+        store.modify_feed("b", add("b")).unwrap();
+        assert!(store.modify_feed("a", add("a")).is_err());
+        drop(shard_lock);
+
+        // Nothing is left locked by the failed attempts
+        store.modify(Box::new(|mut entries| { entries.retain(|entry| entry.feed == "b"); entries })).unwrap();
+        assert!(store.lockfiles().iter().all(|path| !path.exists()));
+        assert_eq!(titles(&store.entries().unwrap()), [("b".to_string(), "Title".to_string())]);
+        assert_eq!(store.read_backup(1).unwrap().len(), 3);
+    }
+}
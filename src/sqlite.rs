@@ -0,0 +1,266 @@
+// SQLite database backend.
+// Entries live in a single table keyed by (feed, id), so modifications only
+// need to touch the rows which actually changed.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time;
+
+use rusqlite::{params, Connection, OpenFlags, TransactionBehavior};
+
+use chrono::{DateTime, Utc};
+
+use crate::{append_unbacked_changes, backup_entries, get_backup_path, Entry, EntryStore, JournalChange, LockWait, SeenIds, SeenModifier, StoreError, StoreOptions};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        feed TEXT NOT NULL,
+        id TEXT NOT NULL,
+        updated TEXT NOT NULL,
+        title TEXT NOT NULL,
+        link TEXT NOT NULL,
+        read INTEGER NOT NULL,
+        starred INTEGER NOT NULL DEFAULT 0,
+        read_at TEXT,
+        first_seen TEXT,
+        PRIMARY KEY (feed, id)
+    );
+    CREATE TABLE IF NOT EXISTS seen (
+        feed TEXT NOT NULL,
+        id TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        PRIMARY KEY (feed, id)
+    );
+";
+
+// Version of SCHEMA, kept in the database's user_version. Older databases are
+// upgraded by MIGRATIONS, the first of which upgrades from version 0.
+const SCHEMA_VERSION: i32 = 3;
+
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS seen (
+        feed TEXT NOT NULL,
+        id TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        PRIMARY KEY (feed, id)
+    );",
+    "ALTER TABLE entries ADD COLUMN starred INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE entries ADD COLUMN read_at TEXT;
+     ALTER TABLE entries ADD COLUMN first_seen TEXT;",
+];
+
+pub struct SqliteStore {
+    path: PathBuf,
+    options: StoreOptions,
+}
+
+impl SqliteStore {
+    pub fn new(path: PathBuf) -> SqliteStore {
+        return SqliteStore::with_options(path, StoreOptions::default());
+    }
+
+    pub fn with_options(path: PathBuf, options: StoreOptions) -> SqliteStore {
+        // SQLite does its own locking, so only the lock wait is used
+        return SqliteStore { path, options };
+    }
+
+    fn error(&self, e: rusqlite::Error) -> StoreError {
+        return StoreError::SqliteError{ source: e, path: self.path.clone() };
+    }
+
+    fn connect(&self, flags: OpenFlags) -> Result<Connection, StoreError> {
+        // Connect, upgrading the schema first if needed.
+        let connection = self.open(flags)?;
+        if get_schema_version(&connection).map_err(|e| self.error(e))? < SCHEMA_VERSION {
+            self.upgrade()?;
+        }
+        return Ok(connection);
+    }
+
+    fn upgrade(&self) -> Result<(), StoreError> {
+        let mut connection = self.open(OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|e| self.error(e))?;
+        // Someone else may have upgraded while we waited for the lock
+        let version = get_schema_version(&transaction).map_err(|e| self.error(e))?;
+        for migration in MIGRATIONS.iter().skip(version as usize) {
+            transaction.execute_batch(migration).map_err(|e| self.error(e))?;
+        }
+        transaction.pragma_update(None, "user_version", SCHEMA_VERSION).map_err(|e| self.error(e))?;
+        return transaction.commit().map_err(|e| self.error(e));
+    }
+
+    fn open(&self, flags: OpenFlags) -> Result<Connection, StoreError> {
+        let connection = Connection::open_with_flags(&self.path, flags)
+            .map_err(|e| self.error(e))?;
+        let busy_timeout = match self.options.lock.wait {
+            LockWait::FailFast => time::Duration::ZERO,
+            LockWait::Timeout(timeout) => timeout,
+            LockWait::Block => time::Duration::from_millis(i32::MAX as u64),
+        };
+        connection.busy_timeout(busy_timeout).map_err(|e| self.error(e))?;
+        return Ok(connection);
+    }
+
+    fn modify_entries<F>(&self, modifier: F, track_seen: bool) -> Result<(), StoreError>
+        where F: FnOnce(Vec<Entry>, &mut SeenIds) -> Vec<Entry>
+    {
+        let mut connection = self.connect(OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        // Take the write lock up front, so that nobody else can modify the
+        // entries between reading them and writing the result.
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|e| self.error(e))?;
+
+        let entries = read_entries(&transaction).map_err(|e| self.error(e))?;
+        let mut old_entries: HashMap<(String, String), Entry> = HashMap::new();
+        for entry in entries.iter() {
+            old_entries.insert((entry.feed.clone(), entry.id.clone()), entry.clone());
+        }
+
+        let mut seen = SeenIds::default();
+        if track_seen {
+            seen = read_seen_ids(&transaction).map_err(|e| self.error(e))?;
+        }
+        let old_seen = seen.clone();
+
+        let modified_entries = modifier(entries.clone(), &mut seen);
+
+        // Backed up while the write lock is held, so the backup is exactly
+        // what is being replaced
+        backup_entries(&self.path, &entries, &modified_entries, self.options.backups)
+            .map_err(|e| StoreError::BackupError{ source: e, path: get_backup_path(&self.path, 1) })?;
+
+        // Write back only the entries which have been added or changed, then
+        // remove anything which wasn't returned.
+        for entry in modified_entries {
+            let key = (entry.feed.clone(), entry.id.clone());
+            if old_entries.remove(&key).as_ref() == Some(&entry) {
+                continue;
+            }
+            transaction.execute(
+                "INSERT OR REPLACE INTO entries (feed, id, updated, title, link, read, starred, read_at, first_seen)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![entry.feed, entry.id, entry.updated, entry.title, entry.link, entry.read, entry.starred,
+                        entry.read_at, entry.first_seen],
+            ).map_err(|e| self.error(e))?;
+        }
+        for (feed, id) in old_entries.into_keys() {
+            transaction.execute("DELETE FROM entries WHERE feed = ?1 AND id = ?2", params![feed, id])
+                .map_err(|e| self.error(e))?;
+        }
+
+        // Likewise for the seen ids
+        if track_seen {
+            seen.expire(self.options.seen_days);
+            let mut old_ids = old_seen.ids;
+            for ((feed, id), last_seen) in seen.ids {
+                if old_ids.remove(&(feed.clone(), id.clone())) == Some(last_seen) {
+                    continue;
+                }
+                transaction.execute("INSERT OR REPLACE INTO seen (feed, id, last_seen) VALUES (?1, ?2, ?3)",
+                                    params![feed, id, last_seen.to_rfc3339()])
+                    .map_err(|e| self.error(e))?;
+            }
+            for (feed, id) in old_ids.into_keys() {
+                transaction.execute("DELETE FROM seen WHERE feed = ?1 AND id = ?2", params![feed, id])
+                    .map_err(|e| self.error(e))?;
+            }
+        }
+
+        return transaction.commit().map_err(|e| self.error(e));
+    }
+}
+
+fn read_seen_ids(connection: &Connection) -> rusqlite::Result<SeenIds> {
+    let mut statement = connection.prepare("SELECT feed, id, last_seen FROM seen")?;
+    let mut rows = statement.query([])?;
+    let mut seen = SeenIds::default();
+    while let Some(row) = rows.next()? {
+        let last_seen: String = row.get(2)?;
+        // As with the TSV backend, unparseable times are ignored
+        if let Ok(last_seen) = DateTime::parse_from_rfc3339(&last_seen) {
+            seen.ids.insert((row.get(0)?, row.get(1)?), last_seen.with_timezone(&Utc));
+        }
+    }
+    return Ok(seen);
+}
+
+fn get_schema_version(connection: &Connection) -> rusqlite::Result<i32> {
+    return connection.pragma_query_value(None, "user_version", |row| row.get(0));
+}
+
+fn read_entries(connection: &Connection) -> rusqlite::Result<Vec<Entry>> {
+    let mut statement = connection.prepare(
+        "SELECT feed, id, updated, title, link, read, starred, read_at, first_seen FROM entries ORDER BY rowid")?;
+    let rows = statement.query_map([], |row| {
+        Ok(Entry {
+            feed: row.get(0)?,
+            id: row.get(1)?,
+            updated: row.get(2)?,
+            title: row.get(3)?,
+            link: row.get(4)?,
+            read: row.get(5)?,
+            starred: row.get(6)?,
+            read_at: row.get(7)?,
+            first_seen: row.get(8)?,
+        })
+    })?;
+    return rows.collect();
+}
+
+impl EntryStore for SqliteStore {
+    fn entries(&self) -> Result<Vec<Entry>, StoreError> {
+        let connection = self.connect(OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        return read_entries(&connection).map_err(|e| self.error(e));
+    }
+
+    fn modify(&self, modifier: Box<dyn FnOnce(Vec<Entry>) -> Vec<Entry> + '_>) -> Result<(), StoreError> {
+        return self.modify_entries(|entries, _| modifier(entries), false);
+    }
+
+    fn modify_seen(&self, modifier: SeenModifier<'_>) -> Result<(), StoreError> {
+        return self.modify_entries(modifier, true);
+    }
+
+    fn seen_ids(&self) -> Result<SeenIds, StoreError> {
+        let connection = self.connect(OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        return read_seen_ids(&connection).map_err(|e| self.error(e));
+    }
+
+    fn read_backup(&self, number: usize) -> Result<Vec<Entry>, StoreError> {
+        return crate::read_backup(&self.path, number).map_err(|e| StoreError::ReadError{ source: e });
+    }
+
+    fn initialize(&self) -> Result<(), StoreError> {
+        // A new database gets the current schema; an existing one is only
+        // upgraded.
+        let connection = self.open(OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE)?;
+        let has_entries: bool = connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'entries')", [], |row| row.get(0))
+            .map_err(|e| self.error(e))?;
+        if has_entries {
+            drop(connection);
+            return self.connect(OpenFlags::SQLITE_OPEN_READ_WRITE).map(|_| ());
+        }
+        connection.execute_batch(SCHEMA).map_err(|e| self.error(e))?;
+        return connection.pragma_update(None, "user_version", SCHEMA_VERSION).map_err(|e| self.error(e));
+    }
+
+    fn set_read(&self, feed_name: &str, entry_id: &str, read: bool) -> Result<(), StoreError> {
+        // As Entry::set_read, an entry which is already read keeps its
+        // original read time
+        let connection = self.connect(OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        let read_at = if read { Some(Utc::now().to_rfc3339()) } else { None };
+        connection.execute("UPDATE entries SET read = ?3, read_at = CASE WHEN read AND ?3 THEN read_at ELSE ?4 END
+                            WHERE feed = ?1 AND id = ?2",
+                           params![feed_name, entry_id, read, read_at.clone()])
+            .map_err(|e| self.error(e))?;
+        // Nothing is backed up, so keep the change for undo to re-apply
+        if self.options.backups > 0 {
+            let change = JournalChange { feed: feed_name.to_string(), id: entry_id.to_string(), read, time: read_at };
+            append_unbacked_changes(&self.path, &[change])
+                .map_err(|e| StoreError::BackupError{ source: e, path: get_backup_path(&self.path, 1) })?;
+        }
+        return Ok(());
+    }
+}
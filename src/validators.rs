@@ -0,0 +1,134 @@
+// HTTP validators.
+// The ETag and Last-Modified headers of a feed's last download are kept in a
+// `validators` file in its directory, and sent back with the next request so
+// that an unchanged feed gets a short 304 Not Modified response instead.
+// The file holds the headers as they appear in a response, which is also how
+// fetch executables report them (eg with `curl -D "$HEADERS"`).
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn read(path: &Path) -> Validators {
+        // Validators from a file; a missing or unreadable file just means a
+        // full download.
+        return match fs::read(path) {
+            Ok(contents) => parse_headers(&String::from_utf8_lossy(&contents)).1,
+            Err(_) => Validators::default(),
+        };
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        // Save the validators, removing the file if there are none.
+        if self.is_empty() {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let mut contents = String::new();
+        if let Some(etag) = &self.etag {
+            contents += &format!("ETag: {}\n", etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            contents += &format!("Last-Modified: {}\n", last_modified);
+        }
+        return fs::write(path, contents);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.etag.is_none() && self.last_modified.is_none();
+    }
+}
+
+pub fn parse_headers(headers: &str) -> (Option<u16>, Validators) {
+    // The status code and validators of the last response in a header dump.
+    // After redirects there is one response per request, each starting with
+    // its status line, and only the last is the feed itself.
+
+    let mut status = None;
+    let mut validators = Validators::default();
+    for line in headers.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with("HTTP/") {
+            status = line.split_whitespace().nth(1).and_then(|code| code.parse().ok());
+            validators = Validators::default();
+            continue;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        if value.is_empty() {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("etag") {
+            validators.etag = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("last-modified") {
+            validators.last_modified = Some(value.to_string());
+        }
+    }
+    return (status, validators);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validators(etag: Option<&str>, last_modified: Option<&str>) -> Validators {
+        return Validators { etag: etag.map(|etag| etag.to_string()), last_modified: last_modified.map(|date| date.to_string()) };
+    }
+
+    #[test]
+    fn parses_a_single_response() {
+        let headers = "HTTP/1.1 200 OK\r\nContent-Type: application/atom+xml\r\nETag: \"abc\"\r\n\
+                       Last-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\n";
+        assert_eq!(parse_headers(headers), (Some(200), validators(Some("\"abc\""), Some("Wed, 21 Oct 2015 07:28:00 GMT"))));
+    }
+
+    #[test]
+    fn keeps_the_last_response_after_redirects() {
+        // Validators from a redirect don't describe the feed
+        let headers = "HTTP/1.1 301 Moved Permanently\r\nLocation: https://example.org/feed\r\nETag: \"redirect\"\r\n\
+                       Last-Modified: Mon, 01 Jan 2001 00:00:00 GMT\r\n\r\n\
+                       HTTP/2 200\r\netag: W/\"feed\"\r\n\r\n";
+        assert_eq!(parse_headers(headers), (Some(200), validators(Some("W/\"feed\""), None)));
+
+        let headers = "HTTP/1.1 302 Found\r\nLocation: /feed\r\n\r\nHTTP/1.1 304 Not Modified\r\nETag: \"abc\"\r\n\r\n";
+        assert_eq!(parse_headers(headers), (Some(304), validators(Some("\"abc\""), None)));
+    }
+
+    #[test]
+    fn matches_names_case_insensitively() {
+        let headers = "HTTP/1.0 200 OK\nETAG:\"upper\"\nlast-MODIFIED :  Tue, 02 Jan 2001 00:00:00 GMT  \n";
+        assert_eq!(parse_headers(headers), (Some(200), validators(Some("\"upper\""), Some("Tue, 02 Jan 2001 00:00:00 GMT"))));
+    }
+
+    #[test]
+    fn ignores_missing_and_empty_values() {
+        // As written by Validators::write, without a status line
+        assert_eq!(parse_headers("ETag: \"abc\"\nLast-Modified:\nnot a header\n"), (None, validators(Some("\"abc\""), None)));
+        assert_eq!(parse_headers("HTTP/1.1 garbage\r\n"), (None, Validators::default()));
+        assert_eq!(parse_headers(""), (None, Validators::default()));
+    }
+
+    #[test]
+    fn writes_readable_validators() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("validators");
+        let written = validators(Some("\"abc\""), Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+        written.write(&path).unwrap();
+        assert_eq!(Validators::read(&path), written);
+
+        Validators::default().write(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(Validators::read(&path), Validators::default());
+    }
+}